@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE refresh_tokens (
+    id SERIAL PRIMARY KEY NOT NULL,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- all the tokens rotated from the same login share a family
+    family_id VARCHAR NOT NULL,
+    -- sha256 of the opaque token, the token itself is never stored
+    token_hash VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX refresh_tokens_family_id_idx ON refresh_tokens (family_id);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE revoked_tokens (
+    -- the jti claim of the revoked JWT
+    jti VARCHAR PRIMARY KEY NOT NULL,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- the exp claim of the revoked JWT, past it the entry is useless
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- bumped to invalidate all the tokens of a user at once
+ALTER TABLE
+    users ADD COLUMN token_version INT NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE login_attempts (
+    -- 'account:<username>' or 'client:<ip address>'
+    subject VARCHAR PRIMARY KEY NOT NULL,
+    -- consecutive failed logins
+    failures INT NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    locked_until TIMESTAMP WITH TIME ZONE
+);
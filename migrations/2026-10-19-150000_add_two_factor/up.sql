@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- RFC 6238 secret, base32 encoded. It is only enabled once the user
+-- proved their authenticator app knows it
+ALTER TABLE
+    users
+ADD
+    COLUMN totp_secret VARCHAR,
+ADD
+    COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- the time step of the last accepted code, so that no code is used twice
+ADD
+    COLUMN totp_last_step BIGINT;
+
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash VARCHAR NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- single-use tokens sent to users, like password reset links
+CREATE TABLE one_time_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- what the token allows, e.g. 'password_reset'
+    purpose VARCHAR NOT NULL,
+    token_hash VARCHAR UNIQUE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX one_time_tokens_user_id_purpose ON one_time_tokens (user_id, purpose);
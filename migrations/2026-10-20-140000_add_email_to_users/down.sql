@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_email_lower;
+
+ALTER TABLE
+    users DROP COLUMN email,
+    DROP COLUMN email_verified;
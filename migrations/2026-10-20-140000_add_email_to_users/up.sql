@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE
+    users
+ADD
+    COLUMN email VARCHAR,
+ADD
+    COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- one account per address, whatever the case
+CREATE UNIQUE INDEX users_email_lower ON users (LOWER(email));
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+-- long-lived tokens for scripts, limited to some scopes
+CREATE TABLE personal_access_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR UNIQUE NOT NULL,
+    -- like 'persons:read'
+    scopes TEXT [] NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- never expires if null
+    expires_at TIMESTAMP WITH TIME ZONE,
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX personal_access_tokens_user_id ON personal_access_tokens (user_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- the renamed duplicates keep their new names
+DROP INDEX users_username_lower;
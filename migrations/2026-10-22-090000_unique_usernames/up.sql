@@ -0,0 +1,81 @@
+-- Your SQL goes here
+-- the same normalization as the API applies from now on,
+-- NORMALIZE needs a UTF8 database
+UPDATE
+    users
+SET
+    username = TRIM(username)
+WHERE
+    username <> TRIM(username);
+
+DO $$ BEGIN
+    IF current_setting('server_encoding') = 'UTF8' THEN
+        UPDATE
+            users
+        SET
+            username = NORMALIZE(username, NFKC)
+        WHERE
+            username <> NORMALIZE(username, NFKC);
+    END IF;
+END $$;
+
+-- the duplicates that already slipped in keep their oldest account,
+-- the others get their id appended to their name, and a counter
+-- if that is somebody's name already; every rename is reported,
+-- for the operators to tell the users their new name
+DO $$
+DECLARE
+    duplicate RECORD;
+    new_username TEXT;
+    attempt INTEGER;
+BEGIN
+    FOR duplicate IN
+        SELECT
+            id,
+            username
+        FROM
+            (
+                SELECT
+                    id,
+                    username,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY LOWER(username)
+                        ORDER BY
+                            id
+                    ) AS rank
+                FROM
+                    users
+            ) AS ranked
+        WHERE
+            ranked.rank > 1
+        ORDER BY
+            id
+    LOOP
+        new_username := duplicate.username || '-' || duplicate.id;
+        attempt := 1;
+        WHILE EXISTS (
+            SELECT
+                1
+            FROM
+                users
+            WHERE
+                LOWER(username) = LOWER(new_username)
+        ) LOOP
+            attempt := attempt + 1;
+            new_username := duplicate.username || '-' || duplicate.id || '-' || attempt;
+        END LOOP;
+        UPDATE
+            users
+        SET
+            username = new_username
+        WHERE
+            id = duplicate.id;
+        RAISE NOTICE 'Renamed the duplicate account % from % to %',
+            duplicate.id,
+            duplicate.username,
+            new_username;
+    END LOOP;
+END $$;
+
+-- one account per username, whatever the case
+CREATE UNIQUE INDEX users_username_lower ON users (LOWER(username));
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE audit_log;
+
+ALTER TABLE
+    users DROP COLUMN role,
+    DROP COLUMN disabled_at,
+    DROP COLUMN password_reset_required;
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+ALTER TABLE
+    users
+ADD
+    COLUMN role VARCHAR NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin')),
+ADD
+    COLUMN disabled_at TIMESTAMP WITH TIME ZONE,
+ADD
+    COLUMN password_reset_required BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- no foreign keys: the trail outlives the accounts
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    -- null when the server did it, like granting the configured admin roles
+    actor_id INT,
+    action VARCHAR NOT NULL,
+    target_user_id INT,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_log_target_user_id ON audit_log (target_user_id);
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- accounts of an OpenID Connect provider, linked to ours
+CREATE TABLE external_identities (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (issuer, subject)
+);
+
+-- logins that went to the provider and haven't come back yet
+CREATE TABLE oidc_logins (
+    id SERIAL PRIMARY KEY,
+    state_hash VARCHAR NOT NULL UNIQUE,
+    code_verifier VARCHAR NOT NULL,
+    nonce VARCHAR NOT NULL,
+    -- set when a logged in user links their account, instead of logging in
+    user_id INT REFERENCES users (id) ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
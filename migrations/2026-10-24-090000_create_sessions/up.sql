@@ -0,0 +1,23 @@
+-- Your SQL goes here
+-- one per login, on a device: the refresh tokens of its family keep it alive
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY NOT NULL,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    family_id VARCHAR NOT NULL UNIQUE,
+    user_agent VARCHAR,
+    ip_address VARCHAR,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- pushed back each time the refresh token is traded
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+-- the logins made before, without their device
+INSERT INTO sessions (user_id, family_id, created_at, last_seen_at, expires_at, revoked_at)
+SELECT user_id, family_id, MIN(created_at), MAX(created_at), MAX(expires_at),
+       CASE WHEN BOOL_AND(revoked_at IS NOT NULL) THEN MAX(revoked_at) END
+FROM refresh_tokens
+GROUP BY user_id, family_id;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX persons_user_id;
+
+ALTER TABLE
+    persons DROP CONSTRAINT persons_user_id_fkey,
+ADD
+    CONSTRAINT persons_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
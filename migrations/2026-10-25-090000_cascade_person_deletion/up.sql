@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- the persons go with the account that registered them, like everything else of it
+ALTER TABLE
+    persons DROP CONSTRAINT persons_user_id_fkey,
+ADD
+    CONSTRAINT persons_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+CREATE INDEX persons_user_id ON persons (user_id);
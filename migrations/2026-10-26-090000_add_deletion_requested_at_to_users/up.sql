@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- set by DELETE /auth/delete, the account is purged once the grace period is over
+ALTER TABLE
+    users
+ADD
+    COLUMN deletion_requested_at TIMESTAMP WITH TIME ZONE;
+
+-- for the sweep
+CREATE INDEX users_deletion_requested_at ON users (deletion_requested_at)
+WHERE
+    deletion_requested_at IS NOT NULL;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- the logins under way were started without a cookie, they can't be finished anymore
+DELETE FROM
+    oidc_logins;
+
+-- the hash of the secret in the cookie of the browser that started the login
+ALTER TABLE
+    oidc_logins
+ADD
+    COLUMN browser_hash VARCHAR NOT NULL;
@@ -23,3 +23,38 @@ pub fn migrate_and_config_db(db_url: &str) -> anyhow::Result<Pool> {
 
     Ok(pool)
 }
+
+// For the tests that need the database, at TEST_DATABASE_URL or else DATABASE_URL:
+// a pool of one connection, in a transaction that is never committed.
+#[cfg(test)]
+pub fn test_pool() -> actix_web::web::Data<Pool> {
+    use diesel::{r2d2::CustomizeConnection, Connection};
+    use std::sync::Once;
+
+    #[derive(Debug)]
+    struct TestTransaction;
+
+    impl CustomizeConnection<DbConnection, r2d2::Error> for TestTransaction {
+        fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+            conn.begin_test_transaction()
+                .map_err(r2d2::Error::QueryError)
+        }
+    }
+
+    static MIGRATIONS: Once = Once::new();
+
+    dotenv::dotenv().ok();
+    let db_url = std::env::var("TEST_DATABASE_URL")
+        .or_else(|_| std::env::var("DATABASE_URL"))
+        .expect("the tests need TEST_DATABASE_URL or DATABASE_URL");
+    MIGRATIONS.call_once(|| {
+        let conn = DbConnection::establish(&db_url).expect("no test database");
+        embedded_migrations::run(&conn).expect("the test database can't be migrated");
+    });
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(TestTransaction))
+        .build(ConnectionManager::<DbConnection>::new(db_url))
+        .expect("no test database");
+    actix_web::web::Data::new(pool)
+}
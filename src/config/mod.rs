@@ -1,8 +1,19 @@
 pub mod db;
+#[macro_use]
 pub mod routes;
 
+use crate::{
+    jwt::{keys::KeyStore, TokenConfig},
+    mailer::{self, Mailer},
+    oidc::OidcProvider,
+    toolbox::{
+        hashing::PasswordHashing, password_policy::PasswordPolicy,
+        session_cookies::CookieConfig, user_cache::UserCache,
+    },
+};
 use anyhow::Context;
-use std::env;
+use chrono::Duration;
+use std::{env, str::FromStr, sync::Arc};
 use actix_web::http::Method;
 
 #[derive(Clone)]
@@ -11,6 +22,39 @@ pub struct Config {
     pub bind_url: String,
     pub allowed_origin: String,
     pub allowed_methods: Vec<Method>,
+    pub jwt_keys: KeyStore,
+    pub tokens: TokenConfig,
+    pub lockout: LockoutConfig,
+    pub password_policy: PasswordPolicy,
+    pub password_hashing: PasswordHashing,
+    pub totp_issuer: String, // shown in the authenticator apps
+    pub mailer: Arc<dyn Mailer>,
+    pub password_reset_url: Option<String>, // the page of the client that takes the token
+    pub public_url: String, // where clients reach the API, for links in emails
+    pub require_email_verification: bool, // before using the /persons routes
+    pub admin_usernames: Vec<String>, // given the admin role at startup
+    pub oidc: Option<OidcProvider>, // login with an identity provider, if set
+    pub session_cookies: Option<CookieConfig>, // tokens in cookies for browsers, if set
+    pub user_cache: UserCache, // of the authentication middleware
+    pub account_deletion: DeletionConfig,
+    pub import_max_bytes: usize, // the largest export document POST /auth/import takes
+}
+
+// brute-force protection of /auth/login
+#[derive(Clone)]
+pub struct LockoutConfig {
+    pub max_failures_per_account: i32,
+    pub max_failures_per_client: i32,
+    pub base_lockout: i64, // seconds, doubled by each further failure
+    pub max_lockout: i64,  // seconds
+    pub failures_forgotten_after: i64, // seconds
+}
+
+// DELETE /auth/delete leaves some time to change one's mind
+#[derive(Clone)]
+pub struct DeletionConfig {
+    pub grace_period: Duration, // to restore the account
+    pub sweep_interval: u64,    // seconds between two purges of the accounts past it
 }
 
 impl Config {
@@ -31,11 +75,144 @@ impl Config {
             Method::DELETE,
         ];
 
+        let jwt_keys = KeyStore::from_env().context("Could not load the JWT keys")?;
+
+        let lockout = LockoutConfig {
+            max_failures_per_account: env_or("LOCKOUT_MAX_FAILURES_PER_ACCOUNT", 5)?,
+            max_failures_per_client: env_or("LOCKOUT_MAX_FAILURES_PER_CLIENT", 20)?,
+            base_lockout: env_or("LOCKOUT_BASE_SECONDS", 30)?,
+            max_lockout: env_or("LOCKOUT_MAX_SECONDS", 60 * 60)?,
+            failures_forgotten_after: env_or(
+                "LOCKOUT_FORGET_AFTER_SECONDS",
+                60 * 60 * 24,
+            )?,
+        };
+
+        let password_policy =
+            PasswordPolicy::from_env().context("Could not set the password policy")?;
+
+        let password_hashing =
+            PasswordHashing::from_env().context("Could not set the password hashing")?;
+
+        let totp_issuer = env_or("TOTP_ISSUER", "ages_api".to_string())?;
+
+        let mailer = mailer::from_env().context("Could not set the mailer")?;
+        let password_reset_url = env::var("PASSWORD_RESET_URL").ok();
+        let public_url = env_or("PUBLIC_URL", format!("http://{}", bind_url))?
+            .trim_end_matches('/')
+            .to_string();
+        let tokens =
+            TokenConfig::from_env(&public_url).context("Could not set the JWT claims")?;
+        let require_email_verification = env_or("REQUIRE_EMAIL_VERIFICATION", false)?;
+        // comma-separated
+        let admin_usernames = env::var("ADMIN_USERNAMES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        let oidc = OidcProvider::from_env(&public_url)
+            .context("Could not set the identity provider")?;
+        let session_cookies =
+            CookieConfig::from_env().context("Could not set the session cookies")?;
+        let user_cache = UserCache::from_env().context("Could not set the user cache")?;
+        let import_max_bytes = env_or("IMPORT_MAX_BYTES", 10 * 1024 * 1024)?;
+        let account_deletion = DeletionConfig {
+            grace_period: Duration::seconds(env_or(
+                "ACCOUNT_DELETION_GRACE_SECONDS",
+                60 * 60 * 24 * 30,
+            )?),
+            sweep_interval: env_or("ACCOUNT_DELETION_SWEEP_SECONDS", 60 * 60)?,
+        };
+        if account_deletion.grace_period < Duration::zero()
+            || account_deletion.sweep_interval == 0
+        {
+            anyhow::bail!("The account deletion settings can't be negative or zero");
+        }
+
         Ok(Self {
             database_url,
             bind_url,
             allowed_origin,
             allowed_methods,
+            jwt_keys,
+            tokens,
+            lockout,
+            password_policy,
+            password_hashing,
+            totp_issuer,
+            mailer,
+            password_reset_url,
+            public_url,
+            require_email_verification,
+            admin_usernames,
+            oidc,
+            session_cookies,
+            user_cache,
+            account_deletion,
+            import_max_bytes,
         })
     }
 }
+
+#[cfg(test)]
+impl Config {
+    // the same settings for every test, whatever the environment
+    pub fn for_tests() -> Self {
+        let public_url = "http://localhost:8080".to_string();
+        Self {
+            database_url: String::new(), // the tests use db::test_pool
+            bind_url: "localhost:8080".to_string(),
+            allowed_origin: "http://localhost:3000".to_string(),
+            allowed_methods: vec![Method::GET, Method::POST, Method::PUT, Method::DELETE],
+            jwt_keys: KeyStore::for_tests("ed25519"),
+            tokens: TokenConfig {
+                issuer: public_url.clone(),
+                audience: "ages_api".to_string(),
+                access_token_lifetime: 60 * 15,
+                refresh_token_lifetime: 60 * 60 * 24 * 30,
+                challenge_token_lifetime: 60 * 5,
+                leeway: 60,
+            },
+            lockout: LockoutConfig {
+                max_failures_per_account: 5,
+                max_failures_per_client: 20,
+                base_lockout: 30,
+                max_lockout: 60 * 60,
+                failures_forgotten_after: 60 * 60 * 24,
+            },
+            password_policy: PasswordPolicy::for_tests(),
+            password_hashing: PasswordHashing::for_tests(),
+            totp_issuer: "ages_api".to_string(),
+            mailer: Arc::new(mailer::file::FileMailer::log_only()),
+            password_reset_url: None,
+            public_url,
+            require_email_verification: false,
+            admin_usernames: Vec::new(),
+            oidc: None,
+            session_cookies: None,
+            // off, for the tests to see what they change in the database at once
+            user_cache: UserCache::new(0, std::time::Duration::from_secs(0)),
+            account_deletion: DeletionConfig {
+                grace_period: Duration::days(30),
+                sweep_interval: 60 * 60,
+            },
+            import_max_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+// for the optional settings
+pub fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{} is not valid in env: '{}'", name, value)),
+        Err(_) => Ok(default),
+    }
+}
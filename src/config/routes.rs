@@ -1,31 +1,416 @@
-use crate::{controllers, toolbox};
-use actix_web::web::{delete, get, post, put, resource, scope, ServiceConfig};
-
-pub fn config_routes(cfg: &mut ServiceConfig) {
-    info!("Configurating the routes...");
-    cfg.service(
-        scope("/auth")
-            .service(resource("/signup").route(post().to(controllers::users::signup)))
-            .service(resource("/login").route(post().to(controllers::users::login)))
-            .service(resource("/update").route(put().to(controllers::users::update)))
-            .service(resource("/delete").route(delete().to(controllers::users::delete))),
-    )
-    .service(
-        scope("/persons")
-            .service(
-                resource("")
-                    .route(get().to(controllers::persons::find_all))
-                    .route(post().to(controllers::persons::create))
-                    .route(put().to(controllers::persons::update)),
-            )
-            .service(
-                resource("/{id}")
-                    .route(get().to(controllers::persons::find))
-                    .route(delete().to(controllers::persons::delete)),
-            ),
-    )
-    .service(scope("/ping").service(resource("").route(get().to(toolbox::ping::ping))));
-    // the "/documentation" route is served in main
-}
-
-pub const IGNORE_ROUTES: [&str; 4] = ["/auth/signup", "/auth/login", "/ping", "/documentation"];
+use crate::{controllers, models::personal_access_token::Scope, toolbox};
+use actix_files::Files;
+use actix_web::{
+    dev::{Factory, ResourceDef},
+    http::Method,
+    web::{resource, route, ServiceConfig},
+    FromRequest, Responder, Route,
+};
+use anyhow::bail;
+use std::{fmt, future::Future};
+
+// who may call a route
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    Public,
+    // a login, personal access tokens are refused
+    Authenticated,
+    // a login, or a personal access token with this scope
+    Scope(Scope),
+    // a login of an administrator
+    Admin,
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Public => f.write_str("public"),
+            Policy::Authenticated => f.write_str("authenticated"),
+            Policy::Scope(scope) => write!(f, "scope {}", scope.as_str()),
+            Policy::Admin => f.write_str("admin"),
+        }
+    }
+}
+
+struct DeclaredRoute {
+    method: Method,
+    path: &'static str,
+    definition: ResourceDef,
+    policy: Policy,
+    route: Box<dyn Fn() -> Route + Send + Sync>,
+}
+
+// a directory served as is, always public
+struct StaticFiles {
+    path: &'static str,
+    definition: ResourceDef,
+    directory: &'static str,
+    index_file: &'static str,
+}
+
+// Every route of the API with its policy. The router and the authentication
+// middleware are both built from it, so that they can't disagree.
+pub struct RouteTable {
+    routes: Vec<DeclaredRoute>,
+    static_files: Vec<StaticFiles>,
+}
+
+impl RouteTable {
+    fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            static_files: Vec::new(),
+        }
+    }
+
+    fn route<F, T, R, U>(
+        mut self,
+        method: Method,
+        path: &'static str,
+        policy: Policy,
+        handler: F,
+    ) -> Self
+    where
+        F: Factory<T, R, U> + Send + Sync,
+        T: FromRequest + 'static,
+        R: Future<Output = U> + 'static,
+        U: Responder + 'static,
+    {
+        let route_method = method.clone();
+        self.routes.push(DeclaredRoute {
+            method,
+            path,
+            definition: ResourceDef::new(path),
+            policy,
+            route: Box::new(move || {
+                route().method(route_method.clone()).to(handler.clone())
+            }),
+        });
+        self
+    }
+
+    fn files(
+        mut self,
+        path: &'static str,
+        directory: &'static str,
+        index_file: &'static str,
+    ) -> Self {
+        self.static_files.push(StaticFiles {
+            path,
+            definition: ResourceDef::prefix(path),
+            directory,
+            index_file,
+        });
+        self
+    }
+
+    // exact matching: "/pingfoo" is not "/ping"
+    // unknown routes need a login, they end up in a 404 or 405 anyway
+    pub fn policy_for(&self, method: &Method, path: &str) -> Policy {
+        if let Some(declared) = self.routes.iter().find(|declared| {
+            declared.method == method && declared.definition.is_match(path)
+        }) {
+            return declared.policy;
+        }
+        if self
+            .static_files
+            .iter()
+            .any(|files| files.definition.is_prefix_match(path).is_some())
+        {
+            return Policy::Public;
+        }
+        Policy::Authenticated
+    }
+
+    // one resource per path, with a route per method
+    pub fn register(&self, cfg: &mut ServiceConfig) {
+        info!("Configurating the routes...");
+        let mut paths: Vec<&str> = Vec::new();
+        for declared in self.routes.iter() {
+            if !paths.contains(&declared.path) {
+                paths.push(declared.path);
+            }
+        }
+        for path in paths {
+            let resource = self
+                .routes
+                .iter()
+                .filter(|declared| declared.path == path)
+                .fold(resource(path), |resource, declared| {
+                    resource.route((declared.route)())
+                });
+            cfg.service(resource);
+        }
+        for files in self.static_files.iter() {
+            cfg.service(
+                Files::new(files.path, files.directory).index_file(files.index_file),
+            );
+        }
+    }
+
+    // run at startup: lists every route with its policy, and refuses duplicates
+    pub fn check(&self) -> anyhow::Result<()> {
+        for (index, declared) in self.routes.iter().enumerate() {
+            if self.routes[..index].iter().any(|earlier| {
+                earlier.method == declared.method && earlier.path == declared.path
+            }) {
+                bail!(
+                    "The route {} {} is declared twice",
+                    declared.method,
+                    declared.path
+                );
+            }
+            info!(
+                "{:<7} {:<34} {}",
+                declared.method.as_str(),
+                declared.path,
+                declared.policy
+            );
+        }
+        for files in self.static_files.iter() {
+            info!(
+                "{:<7} {:<34} {}",
+                "GET",
+                format!("{}/*", files.path),
+                Policy::Public
+            );
+        }
+        Ok(())
+    }
+}
+
+pub fn route_table() -> RouteTable {
+    use controllers::{
+        admin, email, export, jwks, oidc, password_reset, personal_access_tokens,
+        persons, sessions, two_factor, users,
+    };
+    use Policy::{Admin, Authenticated, Public};
+    use Scope::{PersonsRead, PersonsWrite};
+
+    RouteTable::new()
+        .route(Method::POST, "/auth/signup", Public, users::signup)
+        .route(Method::POST, "/auth/login", Public, users::login)
+        .route(
+            Method::POST,
+            "/auth/login/2fa",
+            Public,
+            users::login_second_factor,
+        )
+        .route(Method::GET, "/auth/oidc/login", Public, oidc::login)
+        .route(Method::GET, "/auth/oidc/callback", Public, oidc::callback)
+        .route(Method::POST, "/auth/oidc/link", Authenticated, oidc::link)
+        .route(Method::POST, "/auth/forgot", Public, password_reset::forgot)
+        .route(Method::POST, "/auth/reset", Public, password_reset::reset)
+        .route(Method::POST, "/auth/restore", Public, users::restore)
+        .route(Method::POST, "/auth/refresh", Public, users::refresh)
+        .route(Method::POST, "/auth/logout", Authenticated, users::logout)
+        .route(
+            Method::POST,
+            "/auth/logout/all",
+            Authenticated,
+            users::logout_all,
+        )
+        .route(
+            Method::DELETE,
+            "/auth/lockout",
+            Authenticated,
+            users::unlock,
+        )
+        .route(
+            Method::PUT,
+            "/auth/password",
+            Authenticated,
+            users::change_password,
+        )
+        .route(
+            Method::PUT,
+            "/auth/username",
+            Authenticated,
+            users::change_username,
+        )
+        .route(
+            Method::POST,
+            "/auth/2fa/enroll",
+            Authenticated,
+            two_factor::enroll,
+        )
+        .route(
+            Method::POST,
+            "/auth/2fa/confirm",
+            Authenticated,
+            two_factor::confirm,
+        )
+        .route(
+            Method::POST,
+            "/auth/2fa/disable",
+            Authenticated,
+            two_factor::disable,
+        )
+        .route(Method::GET, "/auth/account", Authenticated, users::account)
+        .route(Method::GET, "/auth/export", Authenticated, export::export)
+        .route(
+            Method::POST,
+            "/auth/import",
+            Policy::Scope(PersonsWrite),
+            export::import,
+        )
+        .route(
+            Method::PUT,
+            "/auth/email",
+            Authenticated,
+            email::change_email,
+        )
+        .route(
+            Method::POST,
+            "/auth/email/resend",
+            Authenticated,
+            email::resend,
+        )
+        .route(Method::GET, "/auth/email/verify", Public, email::verify)
+        .route(
+            Method::GET,
+            "/auth/tokens",
+            Authenticated,
+            personal_access_tokens::find_all,
+        )
+        .route(
+            Method::POST,
+            "/auth/tokens",
+            Authenticated,
+            personal_access_tokens::create,
+        )
+        .route(
+            Method::DELETE,
+            "/auth/tokens/{id}",
+            Authenticated,
+            personal_access_tokens::revoke,
+        )
+        .route(
+            Method::GET,
+            "/auth/sessions",
+            Authenticated,
+            sessions::find_all,
+        )
+        .route(
+            Method::DELETE,
+            "/auth/sessions/{id}",
+            Authenticated,
+            sessions::revoke,
+        )
+        .route(Method::DELETE, "/auth/delete", Authenticated, users::delete)
+        .route(
+            Method::GET,
+            "/persons",
+            Policy::Scope(PersonsRead),
+            persons::find_all,
+        )
+        .route(
+            Method::POST,
+            "/persons",
+            Policy::Scope(PersonsWrite),
+            persons::create,
+        )
+        .route(
+            Method::PUT,
+            "/persons",
+            Policy::Scope(PersonsWrite),
+            persons::update,
+        )
+        .route(
+            Method::GET,
+            "/persons/{id}",
+            Policy::Scope(PersonsRead),
+            persons::find,
+        )
+        .route(
+            Method::DELETE,
+            "/persons/{id}",
+            Policy::Scope(PersonsWrite),
+            persons::delete,
+        )
+        .route(Method::GET, "/admin/users", Admin, admin::find_users)
+        .route(Method::GET, "/admin/users/{id}", Admin, admin::find_user)
+        .route(
+            Method::POST,
+            "/admin/users/{id}/disable",
+            Admin,
+            admin::disable,
+        )
+        .route(
+            Method::POST,
+            "/admin/users/{id}/enable",
+            Admin,
+            admin::enable,
+        )
+        .route(
+            Method::POST,
+            "/admin/users/{id}/restore",
+            Admin,
+            admin::restore,
+        )
+        .route(
+            Method::POST,
+            "/admin/users/{id}/password-reset",
+            Admin,
+            admin::force_password_reset,
+        )
+        .route(Method::GET, "/admin/audit", Admin, admin::audit_log)
+        .route(Method::GET, "/admin/metrics", Admin, admin::metrics)
+        .route(Method::GET, "/ping", Public, toolbox::ping::ping)
+        .route(Method::GET, "/.well-known/jwks.json", Public, jwks::jwks)
+        .files("/documentation", "./openapi", "apicontract.json")
+}
+
+// the application of main, on the settings and the database pool of the tests
+#[cfg(test)]
+macro_rules! test_app {
+    ($config:expr, $pool:expr) => {
+        actix_web::test::init_service(
+            actix_web::App::new()
+                .data($config.clone())
+                .app_data($pool.clone())
+                .app_data(actix_web::web::Data::new(
+                    crate::config::routes::route_table(),
+                ))
+                .wrap(crate::middleware::authentication::Authentication)
+                .configure(|cfg| crate::config::routes::route_table().register(cfg)),
+        )
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_are_matched_exactly() {
+        let table = route_table();
+        assert_eq!(table.policy_for(&Method::GET, "/ping"), Policy::Public);
+        assert_eq!(
+            table.policy_for(&Method::GET, "/pingfoo"),
+            Policy::Authenticated
+        );
+        assert_eq!(
+            table.policy_for(&Method::GET, "/documentation/apicontract.json"),
+            Policy::Public
+        );
+        assert_eq!(
+            table.policy_for(&Method::GET, "/documentation-anything"),
+            Policy::Authenticated
+        );
+        assert_eq!(
+            table.policy_for(&Method::DELETE, "/persons/12"),
+            Policy::Scope(Scope::PersonsWrite)
+        );
+        // the method counts too
+        assert_eq!(
+            table.policy_for(&Method::GET, "/auth/login"),
+            Policy::Authenticated
+        );
+    }
+
+    #[test]
+    fn the_route_table_is_consistent() {
+        assert!(route_table().check().is_ok());
+    }
+}
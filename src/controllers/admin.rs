@@ -0,0 +1,129 @@
+use crate::{
+    config::{db::Pool, Config},
+    controllers::password_reset::password_reset_email,
+    mailer,
+    models::{
+        audit_entry::{AuditEntry, AuditFilter},
+        user::{User, UserSearch},
+    },
+    toolbox::{
+        authenticated_user::AuthenticatedUser, pagination::Page, user_cache::CacheMetrics,
+    },
+};
+use actix_web::{web, HttpResponse, Result};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct Metrics {
+    pub user_cache: CacheMetrics, // of the authentication middleware
+}
+
+// GET HOST/admin/users?search=&limit=&offset=
+pub async fn find_users(
+    search: web::Query<UserSearch>,
+    page: web::Query<Page>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let user_summaries = User::search(admin.uid, &search, &page, &pool)?;
+    Ok(HttpResponse::Ok().json(user_summaries))
+}
+
+// GET HOST/admin/users/{id}
+pub async fn find_user(
+    uid: web::Path<i32>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let user_summary = User::summary(admin.uid, uid.into_inner(), &pool)?;
+    Ok(HttpResponse::Ok().json(user_summary))
+}
+
+// POST HOST/admin/users/{id}/disable
+pub async fn disable(
+    uid: web::Path<i32>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let user_summary = User::disable(admin.uid, uid.into_inner(), &pool)?;
+    config.user_cache.invalidate(user_summary.id);
+    Ok(HttpResponse::Ok().json(user_summary))
+}
+
+// POST HOST/admin/users/{id}/restore
+pub async fn restore(
+    uid: web::Path<i32>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let user_summary = User::restore_by_admin(
+        admin.uid,
+        uid.into_inner(),
+        config.account_deletion.grace_period,
+        &pool,
+    )?;
+    config.user_cache.invalidate(user_summary.id);
+    Ok(HttpResponse::Ok().json(user_summary))
+}
+
+// POST HOST/admin/users/{id}/enable
+pub async fn enable(
+    uid: web::Path<i32>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let user_summary = User::enable(admin.uid, uid.into_inner(), &pool)?;
+    config.user_cache.invalidate(user_summary.id);
+    Ok(HttpResponse::Ok().json(user_summary))
+}
+
+// POST HOST/admin/users/{id}/password-reset
+// the reset link goes to the user's verified address, never to the administrator
+pub async fn force_password_reset(
+    uid: web::Path<i32>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let (user, reset_token) =
+        User::force_password_reset(admin.uid, uid.into_inner(), &pool)?;
+    config.user_cache.invalidate(user.id);
+    match user.contact_address() {
+        Some(address) => {
+            mailer::send_later(
+                config.mailer.clone(),
+                password_reset_email(address, &reset_token, &config),
+            );
+            Ok(HttpResponse::Ok().body(format!(
+                "'{}' has to reset their password, a reset link is on its way",
+                user.username
+            )))
+        }
+        None => Ok(HttpResponse::Ok().body(format!(
+            "'{}' has to reset their password, but has no verified address to email",
+            user.username
+        ))),
+    }
+}
+
+// GET HOST/admin/audit?user_id=&limit=&offset=
+pub async fn audit_log(
+    filter: web::Query<AuditFilter>,
+    page: web::Query<Page>,
+    admin: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let entries = AuditEntry::find(admin.uid, &filter, &page, &pool)?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+// GET HOST/admin/metrics
+// counted since the server started
+pub async fn metrics(config: web::Data<Config>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(Metrics {
+        user_cache: config.user_cache.metrics(),
+    }))
+}
@@ -0,0 +1,81 @@
+use crate::{
+    config::{db::Pool, Config},
+    mailer::{self, Email},
+    models::user::{ReceivedEmailChange, User},
+    toolbox::{authenticated_user::AuthenticatedUser, errors::CustomError},
+};
+use actix_web::{web, HttpResponse, Result};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+pub struct VerificationQuery {
+    pub token: String,
+}
+
+// PUT HOST/auth/email
+pub async fn change_email(
+    json_change: web::Json<ReceivedEmailChange>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let updated_user = User::change_email(user.uid, json_change.0, &pool)?;
+    config.user_cache.invalidate(updated_user.id);
+    if updated_user.email.is_some() {
+        send_verification(updated_user.id, &pool, &config)?;
+    }
+    Ok(HttpResponse::Ok().json(updated_user.account()))
+}
+
+// POST HOST/auth/email/resend
+pub async fn resend(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    send_verification(user.uid, &pool, &config)?;
+    Ok(HttpResponse::Accepted().body("A verification link is on its way"))
+}
+
+// GET HOST/auth/email/verify?token=...
+// the link of the verification email
+pub async fn verify(
+    query: web::Query<VerificationQuery>,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let user = User::verify_email(&query.token, &pool)?;
+    config.user_cache.invalidate(user.id);
+    Ok(HttpResponse::Ok().body(format!(
+        "The email address of '{}' is verified",
+        user.username
+    )))
+}
+
+// also used on signup
+pub fn send_verification(
+    uid: i32,
+    pool: &web::Data<Pool>,
+    config: &Config,
+) -> Result<(), CustomError> {
+    let (address, verification_token) = User::start_email_verification(uid, pool)?;
+    let link = format!(
+        "{}/auth/email/verify?token={}",
+        config.public_url, verification_token
+    );
+    mailer::send_later(
+        config.mailer.clone(),
+        Email {
+            to: address,
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Please confirm this address is yours by opening this link:\n\n\
+                 {}\n\n\
+                 It expires in two days. \
+                 If you didn't give this address, you can ignore this email.",
+                link
+            ),
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use crate::{
+    config::{db::Pool, Config},
+    models::{
+        export::Part,
+        import::{ImportDocument, ImportOptions},
+    },
+    toolbox::{authenticated_user::AuthenticatedUser, errors::CustomError},
+};
+use actix_web::{
+    error::BlockingError,
+    http::header,
+    web::{self, Bytes, BytesMut},
+    HttpResponse, Result,
+};
+use futures::{stream, StreamExt};
+
+// GET HOST/auth/export
+// everything we store about the user, in the versioned document of models::export.
+// It is streamed: a failure halfway leaves a truncated document, which doesn't parse.
+pub async fn export(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let (_, _, current_session_id) = user.session()?;
+    let uid = user.uid;
+    let chunks = stream::unfold(Part::Head, move |part| {
+        let pool = pool.clone();
+        async move {
+            if let Part::Done = part {
+                return None;
+            }
+            let written = web::block(move || -> Result<_, CustomError> {
+                let conn = pool.get()?;
+                part.write(uid, Some(current_session_id), &conn)
+            })
+            .await;
+            Some(match written {
+                Ok((chunk, next)) => (Ok(Bytes::from(chunk)), next),
+                Err(error) => (Err(error), Part::Done),
+            })
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"ages_api-export-{}.json\"", uid),
+        )
+        .streaming(Box::pin(chunks)))
+}
+
+// POST HOST/auth/import?mode=merge|replace
+// recreates the persons of an export document, of this deployment or another one
+pub async fn import(
+    options: web::Query<ImportOptions>,
+    mut payload: web::Payload,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    // larger than the JSON bodies of the other routes
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > config.import_max_bytes {
+            return Err(CustomError::new(
+                413,
+                format!(
+                    "The document is larger than {} bytes",
+                    config.import_max_bytes
+                ),
+            )
+            .into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let document: ImportDocument = serde_json::from_slice(&body)
+        .map_err(|error| CustomError::new(400, format!("Invalid document: {}", error)))?;
+
+    let uid = user.uid;
+    let mode = options.mode;
+    let report = web::block(move || document.import(uid, mode, &pool))
+        .await
+        .map_err(|error| match error {
+            BlockingError::Error(error) => error,
+            BlockingError::Canceled => {
+                CustomError::new(503, "The import was interrupted".to_string())
+            }
+        })?;
+    Ok(HttpResponse::Ok().json(report))
+}
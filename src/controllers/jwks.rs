@@ -0,0 +1,8 @@
+use crate::config::Config;
+use actix_web::{web, HttpResponse};
+
+// GET HOST/.well-known/jwks.json
+// the public keys other services need to verify our tokens, shared secrets excluded
+pub async fn jwks(config: web::Data<Config>) -> HttpResponse {
+    HttpResponse::Ok().json(config.jwt_keys.jwk_set())
+}
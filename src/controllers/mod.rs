@@ -1,2 +1,11 @@
+pub mod admin;
+pub mod email;
+pub mod export;
+pub mod jwks;
+pub mod oidc;
+pub mod password_reset;
+pub mod personal_access_tokens;
 pub mod persons;
+pub mod sessions;
+pub mod two_factor;
 pub mod users;
@@ -0,0 +1,385 @@
+use crate::{
+    config::{db::Pool, Config},
+    jwt::{generate_challenge_response, generate_token_response},
+    models::{
+        external_identity::ExternalIdentity,
+        oidc_login::{OidcLogin, LOGIN_LIFETIME},
+        session::{ClientInfo, Session},
+    },
+    oidc::OidcProvider,
+    toolbox::{
+        authenticated_user::AuthenticatedUser, errors::CustomError, session_cookies,
+    },
+};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    http::header,
+    web, HttpMessage, HttpRequest, HttpResponse, ResponseError, Result,
+};
+use serde::{Deserialize, Serialize};
+
+// holds the secret that binds a login to the browser that started it
+static BROWSER_COOKIE: &str = "ages_oidc";
+static BROWSER_COOKIE_PATH: &str = "/auth/oidc";
+
+#[derive(Deserialize, Debug)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>, // when the user refused, or the provider failed
+    pub error_description: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthorizationUrl {
+    pub authorization_url: String,
+}
+
+// GET HOST/auth/oidc/login
+// sends the browser to the provider
+pub async fn login(
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let provider = configured_provider(&config)?;
+    let (authorization_url, browser_secret) = start(None, provider, &pool).await?;
+    Ok(HttpResponse::Found()
+        .header(header::LOCATION, authorization_url)
+        .cookie(browser_cookie(browser_secret, LOGIN_LIFETIME))
+        .finish())
+}
+
+// POST HOST/auth/oidc/link
+// the provider's account will log in to this one, once the user has been there
+pub async fn link(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let provider = configured_provider(&config)?;
+    let (authorization_url, browser_secret) =
+        start(Some(user.uid), provider, &pool).await?;
+    Ok(HttpResponse::Ok()
+        .cookie(browser_cookie(browser_secret, LOGIN_LIFETIME))
+        .json(AuthorizationUrl { authorization_url }))
+}
+
+// GET HOST/auth/oidc/callback?code=...&state=...
+// where the provider sends the browser back, the cookie is cleared whatever happens
+pub async fn callback(
+    query: web::Query<CallbackQuery>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let mut response = match finish(&query, &request, &pool, &config).await {
+        Ok(response) => response,
+        Err(error) => error.error_response(),
+    };
+    response.add_cookie(&browser_cookie(String::new(), 0))?;
+    Ok(response)
+}
+
+async fn finish(
+    query: &CallbackQuery,
+    request: &HttpRequest,
+    pool: &web::Data<Pool>,
+    config: &Config,
+) -> Result<HttpResponse, CustomError> {
+    let provider = configured_provider(config)?;
+    if let Some(error) = &query.error {
+        return Err(CustomError::new(
+            401,
+            format!(
+                "The identity provider refused the login: {} {}",
+                error,
+                query.error_description.as_deref().unwrap_or_default()
+            ),
+        ));
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return Err(CustomError::new(
+                400,
+                "A code and a state are needed".to_string(),
+            ))
+        }
+    };
+    let browser_secret = request.cookie(BROWSER_COOKIE).ok_or_else(|| {
+        CustomError::new(
+            400,
+            "This browser didn't start the login, start again".to_string(),
+        )
+    })?;
+    let oidc_login = consume(state, browser_secret.value(), pool)?;
+
+    let claims = provider
+        .exchange_code(code, &oidc_login.code_verifier, &oidc_login.nonce)
+        .await?;
+
+    if let Some(uid) = oidc_login.user_id {
+        ExternalIdentity::link(uid, &claims, pool)?;
+        return Ok(HttpResponse::Ok().body("The identity is linked to the account"));
+    }
+
+    let logged_user = ExternalIdentity::sign_in(&claims, provider.create_accounts, pool)?;
+    logged_user.ensure_active()?;
+
+    // the provider doesn't replace our own second factor
+    if logged_user.totp_enabled {
+        let json_challenge_response = generate_challenge_response(&logged_user, config)?;
+        return Ok(HttpResponse::Ok().json(json_challenge_response));
+    }
+    let (session, refresh_token) = Session::start(
+        logged_user.id,
+        ClientInfo::from_request(request),
+        config.tokens.refresh_token_lifetime,
+        pool,
+    )?;
+    let json_token_response =
+        generate_token_response(&logged_user, session.id, refresh_token, config)?;
+    Ok(session_cookies::token_response(json_token_response, config))
+}
+
+// the URL to send the browser to, and the secret for its cookie
+async fn start(
+    link_uid: Option<i32>,
+    provider: &OidcProvider,
+    pool: &web::Data<Pool>,
+) -> Result<(String, String), CustomError> {
+    let (state, browser_secret, oidc_login) = {
+        let conn = pool.get()?;
+        OidcLogin::start(link_uid, &conn)?
+    };
+    let authorization_url = provider
+        .authorization_url(&state, &oidc_login.nonce, &oidc_login.code_verifier)
+        .await?;
+    Ok((authorization_url, browser_secret))
+}
+
+fn consume(
+    state: &str,
+    browser_secret: &str,
+    pool: &web::Data<Pool>,
+) -> Result<OidcLogin, CustomError> {
+    let conn = pool.get()?;
+    OidcLogin::consume(state, browser_secret, &conn)?.ok_or_else(|| {
+        CustomError::new(400, "Unknown or expired login, start again".to_string())
+    })
+}
+
+// the provider sends the browser back with a top-level GET from its own site,
+// which the lax mode lets the cookie along with
+fn browser_cookie(browser_secret: String, max_age: i64) -> Cookie<'static> {
+    Cookie::build(BROWSER_COOKIE, browser_secret)
+        .path(BROWSER_COOKIE_PATH)
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(max_age)
+        .finish()
+}
+
+fn configured_provider(config: &Config) -> Result<&OidcProvider, CustomError> {
+    config.oidc.as_ref().ok_or_else(|| {
+        CustomError::new(
+            404,
+            "Login with an identity provider is not configured".to_string(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::db, jwt::keys::KeyStore};
+    use actix_web::{
+        dev::ServiceResponse,
+        http::StatusCode,
+        test::{self, TestRequest, TestServer},
+        App,
+    };
+    use chrono::Utc;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use url::{form_urlencoded, Url};
+    use uuid::Uuid;
+
+    // lets anybody in: the code it takes back is the nonce to put in the id token
+    fn identity_provider() -> TestServer {
+        test::start(|| {
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery),
+                )
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        })
+    }
+
+    fn issuer(request: &HttpRequest) -> String {
+        format!("http://{}", request.connection_info().host())
+    }
+
+    async fn discovery(request: HttpRequest) -> HttpResponse {
+        let issuer = issuer(&request);
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        }))
+    }
+
+    async fn jwks() -> HttpResponse {
+        HttpResponse::Ok().json(KeyStore::for_tests("rsa").jwk_set())
+    }
+
+    async fn token(
+        request: HttpRequest,
+        form: web::Form<HashMap<String, String>>,
+    ) -> HttpResponse {
+        let code = &form["code"];
+        if code == "refused" {
+            return HttpResponse::BadRequest().body("invalid_grant: internal details");
+        }
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "iss": issuer(&request),
+            "sub": Uuid::new_v4().to_string(),
+            "aud": "ages_api",
+            "iat": now,
+            "exp": now + 300,
+            "nonce": code,
+            "preferred_username": format!("oidc-{}", Uuid::new_v4()),
+        });
+        let header = Header {
+            kid: Some("rsa".to_string()),
+            ..Header::new(Algorithm::RS256)
+        };
+        let key = EncodingKey::from_rsa_pem(include_bytes!("../jwt/test_keys/rsa.pem"))
+            .unwrap();
+        let id_token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+        HttpResponse::Ok().json(json!({ "id_token": id_token, "token_type": "Bearer" }))
+    }
+
+    fn config(identity_provider: &TestServer) -> Config {
+        let mut config = Config::for_tests();
+        config.oidc = Some(OidcProvider::for_tests(
+            identity_provider.url("/.well-known/openid-configuration"),
+        ));
+        config
+    }
+
+    fn browser_cookie_of(response: &ServiceResponse) -> Option<Cookie<'static>> {
+        response
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == BROWSER_COOKIE)
+            .map(Cookie::into_owned)
+    }
+
+    fn callback_uri(code: &str, state: &str) -> String {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("code", code)
+            .append_pair("state", state)
+            .finish();
+        format!("/auth/oidc/callback?{}", query)
+    }
+
+    #[actix_rt::test]
+    async fn only_the_browser_that_started_the_login_finishes_it() {
+        let identity_provider = identity_provider();
+        let config = config(&identity_provider);
+        let pool = db::test_pool();
+        let mut app = test_app!(config, pool).await;
+
+        let request = TestRequest::get().uri("/auth/oidc/login").to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let query: HashMap<_, _> = Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        let (state, nonce) = (&query["state"], &query["nonce"]);
+        let cookie = browser_cookie_of(&response).unwrap();
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+
+        // another browser, sent to the callback with the state of this login
+        let without_cookie = TestRequest::get().uri(&callback_uri(nonce, state));
+        let with_another_cookie = TestRequest::get()
+            .uri(&callback_uri(nonce, state))
+            .cookie(Cookie::new(BROWSER_COOKIE, "another secret"));
+        for request in [without_cookie, with_another_cookie] {
+            let response = test::call_service(&mut app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let request = TestRequest::get()
+            .uri(&callback_uri(nonce, state))
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cleared = browser_cookie_of(&response).unwrap();
+        assert_eq!(cleared.value(), "");
+        assert_eq!(cleared.max_age().unwrap().num_seconds(), 0);
+        let body: serde_json::Value =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert!(body["token"].is_string());
+
+        // the state was used up
+        let request = TestRequest::get()
+            .uri(&callback_uri(nonce, state))
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn what_the_provider_says_of_a_refused_code_stays_in_the_logs() {
+        let identity_provider = identity_provider();
+        let config = config(&identity_provider);
+        let pool = db::test_pool();
+        let mut app = test_app!(config, pool).await;
+
+        let request = TestRequest::get().uri("/auth/oidc/login").to_request();
+        let response = test::call_service(&mut app, request).await;
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let state = Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .unwrap()
+            .1
+            .into_owned();
+        let cookie = browser_cookie_of(&response).unwrap();
+
+        let request = TestRequest::get()
+            .uri(&callback_uri("refused", &state))
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = test::read_body(response).await;
+        assert!(!String::from_utf8_lossy(&body).contains("internal details"));
+    }
+}
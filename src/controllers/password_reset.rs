@@ -0,0 +1,77 @@
+use crate::{
+    config::{db::Pool, Config},
+    mailer::Email,
+    models::{
+        login_attempt::{account_subject, LoginAttempt},
+        user::{ReceivedForgottenPassword, ReceivedPasswordReset, User},
+    },
+    toolbox::errors::CustomError,
+};
+use actix_web::{web, HttpResponse, Result};
+
+// POST HOST/auth/forgot
+// The answer is the same whether the user exists or not, and as fast: the account
+// is looked up, and the email sent, in the background.
+pub async fn forgot(
+    json_forgotten: web::Json<ReceivedForgottenPassword>,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let forgotten = json_forgotten.into_inner();
+    actix_rt::spawn(async move {
+        let sent = web::block(move || -> Result<(), CustomError> {
+            if let Some((address, reset_token)) =
+                User::request_password_reset(&forgotten, &pool)?
+            {
+                let email = password_reset_email(&address, &reset_token, &config);
+                config.mailer.send(&email)?;
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(error) = sent {
+            error!("Could not send a password reset: {}", error);
+        }
+    });
+    Ok(HttpResponse::Accepted()
+        .body("If this account exists and has an address, a reset link is on its way"))
+}
+
+// POST HOST/auth/reset
+pub async fn reset(
+    json_reset: web::Json<ReceivedPasswordReset>,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let user = User::reset_password(
+        json_reset.0,
+        &config.password_policy,
+        &config.password_hashing,
+        &pool,
+    )?;
+    config.user_cache.invalidate(user.id);
+    // whoever was guessing the password, the account is back in its owner's hands
+    LoginAttempt::clear(&account_subject(&user.username), &pool)?;
+    Ok(HttpResponse::Ok().body(format!(
+        "The password of '{}' has been reset, you can log in",
+        user.username
+    )))
+}
+
+pub fn password_reset_email(address: &str, reset_token: &str, config: &Config) -> Email {
+    let link = match &config.password_reset_url {
+        Some(url) => format!("{}?token={}", url, reset_token),
+        None => format!("Your reset token: {}", reset_token),
+    };
+    Email {
+        to: address.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone, hopefully you, asked to reset your password.\n\n\
+             {}\n\n\
+             This expires in an hour. \
+             If you didn't ask for it, you can ignore this email.",
+            link
+        ),
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    config::db::Pool,
+    models::personal_access_token::{PersonalAccessToken, ReceivedPersonalAccessToken},
+    toolbox::authenticated_user::AuthenticatedUser,
+};
+use actix_web::{web, HttpResponse, Result};
+
+// GET HOST/auth/tokens
+pub async fn find_all(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let personal_access_tokens = PersonalAccessToken::find_all(user.uid, &pool)?;
+    Ok(HttpResponse::Ok().json(personal_access_tokens))
+}
+
+// POST HOST/auth/tokens
+pub async fn create(
+    json_token: web::Json<ReceivedPersonalAccessToken>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let created_token = PersonalAccessToken::create(user.uid, json_token.0, &pool)?;
+    Ok(HttpResponse::Ok().json(created_token))
+}
+
+// DELETE HOST/auth/tokens/{id}
+pub async fn revoke(
+    token_id: web::Path<i32>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let revoked_token =
+        PersonalAccessToken::revoke(user.uid, token_id.into_inner(), &pool)?;
+    Ok(HttpResponse::Ok().body(format!(
+        "Revoked the personal access token '{}'",
+        revoked_token.name
+    )))
+}
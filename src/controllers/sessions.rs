@@ -0,0 +1,29 @@
+use crate::{
+    config::db::Pool,
+    models::session::{Session, SessionFilter},
+    toolbox::authenticated_user::AuthenticatedUser,
+};
+use actix_web::{web, HttpResponse, Result};
+
+// GET HOST/auth/sessions?all=
+// the devices the user is logged in on, or every login with all=true
+pub async fn find_all(
+    filter: web::Query<SessionFilter>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let (_, _, current_session_id) = user.session()?;
+    let sessions = Session::find_all(user.uid, Some(current_session_id), &filter, &pool)?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// DELETE HOST/auth/sessions/{id}
+// logs that device out, which can be this one
+pub async fn revoke(
+    session_id: web::Path<i32>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    Session::revoke(user.uid, session_id.into_inner(), &pool)?;
+    Ok(HttpResponse::Ok().body("The session has been revoked"))
+}
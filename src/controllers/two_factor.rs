@@ -0,0 +1,36 @@
+use crate::{
+    config::{db::Pool, Config},
+    models::user::{ReceivedTotpCode, User},
+    toolbox::authenticated_user::AuthenticatedUser,
+};
+use actix_web::{web, HttpResponse, Result};
+
+// POST HOST/auth/2fa/enroll
+pub async fn enroll(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let enrollment = User::enroll_totp(user.uid, &config.totp_issuer, &pool)?;
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+// POST HOST/auth/2fa/confirm
+pub async fn confirm(
+    json_code: web::Json<ReceivedTotpCode>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let recovery_codes = User::confirm_totp(user.uid, &json_code.code, &pool)?;
+    Ok(HttpResponse::Ok().json(recovery_codes))
+}
+
+// POST HOST/auth/2fa/disable
+pub async fn disable(
+    json_code: web::Json<ReceivedTotpCode>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    User::disable_totp(user.uid, &json_code.code, &pool)?;
+    Ok(HttpResponse::Ok().body("Two-factor authentication is disabled"))
+}
@@ -1,20 +1,42 @@
 use crate::{
-    config::db::Pool,
-    jwt::generate_token_response,
+    config::{db::Pool, Config},
+    controllers::email::send_verification,
+    jwt::{generate_challenge_response, generate_token_response, ChallengeToken},
     models::{
-        person::Person,
-        user::{ReceivedUser, User},
+        login_attempt::{account_subject, client_subject, LoginAttempt},
+        refresh_token::{ReceivedRefreshToken, RefreshToken},
+        revoked_token::RevokedToken,
+        session::{ClientInfo, Session},
+        user::{ReceivedPasswordChange, ReceivedUser, ReceivedUsernameChange, User},
+    },
+    toolbox::{
+        authenticated_user::AuthenticatedUser, errors::CustomError, session_cookies,
     },
-    toolbox::uid_extractor::get_uid_from_request,
 };
 use actix_web::{web, HttpRequest, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedChallengeAnswer {
+    pub challenge_token: String,
+    pub code: String, // from the authenticator app, or a recovery code
+}
 
 // POST HOST/auth/signup
 pub async fn signup(
     received_user: web::Json<ReceivedUser>,
     pool: web::Data<Pool>,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse> {
-    let registered_user = User::signup(received_user.0, &pool)?;
+    let registered_user = User::signup(
+        received_user.0,
+        &config.password_policy,
+        &config.password_hashing,
+        &pool,
+    )?;
+    if registered_user.email.is_some() {
+        send_verification(registered_user.id, &pool, &config)?;
+    }
     Ok(HttpResponse::Ok().body(format!(
         "Sucessfully registered the user '{}'",
         registered_user.username
@@ -24,41 +46,305 @@ pub async fn signup(
 // POST HOST/auth/login
 pub async fn login(
     json_login: web::Json<ReceivedUser>,
+    request: HttpRequest,
     pool: web::Data<Pool>,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse> {
     debug!("We received this login request: {:#?}", json_login);
     let received_login = json_login.0;
 
-    let logged_user = User::login(&received_login, &pool)?;
-    let json_token_response = generate_token_response(&logged_user)?;
-    Ok(HttpResponse::Ok().json(json_token_response))
+    let subjects = lockout_subjects(&received_login.username, &request, &config);
+    ensure_not_locked(&subjects, &pool)?;
+
+    let logged_user = match User::login(&received_login, &config.password_hashing, &pool)
+    {
+        Ok(user) => user,
+        Err(error) => {
+            record_failures(&subjects, error.error_status_code, &config, &pool)?;
+            return Err(error.into());
+        }
+    };
+
+    // the lockout stays until the second factor is right too
+    if logged_user.totp_enabled {
+        let json_challenge_response = generate_challenge_response(&logged_user, &config)?;
+        return Ok(HttpResponse::Ok().json(json_challenge_response));
+    }
+    LoginAttempt::clear(&account_subject(&logged_user.username), &pool)?;
+
+    let (session, refresh_token) = Session::start(
+        logged_user.id,
+        ClientInfo::from_request(&request),
+        config.tokens.refresh_token_lifetime,
+        &pool,
+    )?;
+    let json_token_response =
+        generate_token_response(&logged_user, session.id, refresh_token, &config)?;
+    Ok(session_cookies::token_response(
+        json_token_response,
+        &config,
+    ))
+}
+
+// POST HOST/auth/login/2fa
+pub async fn login_second_factor(
+    json_answer: web::Json<ReceivedChallengeAnswer>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let challenge =
+        ChallengeToken::decode_from_string(&json_answer.challenge_token, &config)?;
+
+    let subjects = lockout_subjects(&challenge.username, &request, &config);
+    ensure_not_locked(&subjects, &pool)?;
+
+    let logged_user = match User::login_second_factor(
+        challenge.uid,
+        challenge.ver,
+        &json_answer.code,
+        &pool,
+    ) {
+        Ok(user) => user,
+        Err(error) => {
+            record_failures(&subjects, error.error_status_code, &config, &pool)?;
+            return Err(error.into());
+        }
+    };
+    LoginAttempt::clear(&account_subject(&logged_user.username), &pool)?;
+
+    let (session, refresh_token) = Session::start(
+        logged_user.id,
+        ClientInfo::from_request(&request),
+        config.tokens.refresh_token_lifetime,
+        &pool,
+    )?;
+    let json_token_response =
+        generate_token_response(&logged_user, session.id, refresh_token, &config)?;
+    Ok(session_cookies::token_response(
+        json_token_response,
+        &config,
+    ))
+}
+
+// GET HOST/auth/account
+pub async fn account(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let found_user = User::find(user.uid, &pool)?;
+    Ok(HttpResponse::Ok().json(found_user.account()))
+}
+
+// POST HOST/auth/refresh
+// browser clients in the session cookies mode send the refresh token in its cookie
+pub async fn refresh(
+    json_refresh: Option<web::Json<ReceivedRefreshToken>>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let received_refresh_token = match json_refresh {
+        Some(json_refresh) => json_refresh.0.refresh_token,
+        None => session_cookies::refresh_token(&request, &config)?.ok_or_else(|| {
+            CustomError::new(400, "A refresh token is needed".to_string())
+        })?,
+    };
+    let (user, session_id, refresh_token) = RefreshToken::rotate(
+        &received_refresh_token,
+        config.tokens.refresh_token_lifetime,
+        &pool,
+    )?;
+    let json_token_response =
+        generate_token_response(&user, session_id, refresh_token, &config)?;
+    Ok(session_cookies::token_response(
+        json_token_response,
+        &config,
+    ))
+}
+
+// POST HOST/auth/logout
+// revokes the JWT and its session
+pub async fn logout(
+    json_refresh: Option<web::Json<ReceivedRefreshToken>>,
+    request: HttpRequest,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let (jti, expires_at, session_id) = user.session()?;
+
+    RevokedToken::revoke(jti, user.uid, expires_at, &pool)?;
+    Session::revoke(user.uid, session_id, &pool)?;
+    let received_refresh_token = match json_refresh {
+        Some(json_refresh) => Some(json_refresh.0.refresh_token),
+        None => session_cookies::refresh_token(&request, &config)?,
+    };
+    if let Some(received_refresh_token) = received_refresh_token {
+        RefreshToken::revoke_family_of(user.uid, &received_refresh_token, &pool)?;
+    }
+    Ok(session_cookies::clear(&mut HttpResponse::Ok(), &config)
+        .body(format!("Logged out the user '{}'", user.username)))
+}
+
+// POST HOST/auth/logout/all
+pub async fn logout_all(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    User::revoke_all_tokens(user.uid, &pool)?;
+    config.user_cache.invalidate(user.uid);
+    Ok(
+        session_cookies::clear(&mut HttpResponse::Ok(), &config).body(format!(
+            "Logged out the user '{}' from all devices",
+            user.username
+        )),
+    )
 }
 
-// PUT /auth/update
-pub async fn update(
-    json_user: web::Json<ReceivedUser>,
+// DELETE HOST/auth/lockout
+// from a device still logged in, lift the lockout caused by someone guessing the password
+pub async fn unlock(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    LoginAttempt::clear(&account_subject(&user.username), &pool)?;
+    Ok(HttpResponse::Ok().body(format!("Unlocked the user '{}'", user.username)))
+}
+
+// PUT /auth/password
+// the other devices are logged out, this one gets fresh tokens
+pub async fn change_password(
+    json_change: web::Json<ReceivedPasswordChange>,
     request: HttpRequest,
+    user: AuthenticatedUser,
     pool: web::Data<Pool>,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse> {
-    let uid = get_uid_from_request(&request)?;
+    let updated_user = User::change_password(
+        user.uid,
+        json_change.0,
+        &config.password_policy,
+        &config.password_hashing,
+        &pool,
+    )?;
+    config.user_cache.invalidate(updated_user.id);
+    let (session, refresh_token) = Session::start(
+        updated_user.id,
+        ClientInfo::from_request(&request),
+        config.tokens.refresh_token_lifetime,
+        &pool,
+    )?;
+    let json_token_response =
+        generate_token_response(&updated_user, session.id, refresh_token, &config)?;
+    Ok(session_cookies::token_response(
+        json_token_response,
+        &config,
+    ))
+}
 
-    let received_user = json_user.0;
-    let updated_user = User::update(uid, received_user, &pool)?;
+// PUT /auth/username
+pub async fn change_username(
+    json_change: web::Json<ReceivedUsernameChange>,
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let updated_user = User::change_username(user.uid, json_change.0, &pool)?;
+    config.user_cache.invalidate(updated_user.id);
     Ok(HttpResponse::Ok().body(format!(
-        "Successfully updated the user '{}'",
+        "Successfully renamed the user '{}'",
         updated_user.username
     )))
 }
 
 // DELETE /auth/delete
-pub async fn delete(request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse> {
-    let uid = get_uid_from_request(&request)?;
+// the account is only purged once the grace period is over, until then it can be restored
+pub async fn delete(
+    user: AuthenticatedUser,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let pending_deletion =
+        User::request_deletion(user.uid, config.account_deletion.grace_period, &pool)?;
+    config.user_cache.invalidate(user.uid);
+    Ok(HttpResponse::Ok().json(pending_deletion))
+}
 
-    let deleted_persons = Person::delete_all_with_uid(uid, &pool)?;
-    let deleted_user = User::delete(uid, &pool)?;
+// POST HOST/auth/restore
+// guarded against guessing like the login, since it takes the password too
+pub async fn restore(
+    json_login: web::Json<ReceivedUser>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let received_login = json_login.0;
+
+    let subjects = lockout_subjects(&received_login.username, &request, &config);
+    ensure_not_locked(&subjects, &pool)?;
+
+    let restored_user = match User::restore(
+        &received_login,
+        &config.password_hashing,
+        config.account_deletion.grace_period,
+        &pool,
+    ) {
+        Ok(user) => user,
+        Err(error) => {
+            record_failures(&subjects, error.error_status_code, &config, &pool)?;
+            return Err(error.into());
+        }
+    };
+    LoginAttempt::clear(&account_subject(&restored_user.username), &pool)?;
+    config.user_cache.invalidate(restored_user.id);
+    Ok(HttpResponse::Ok().body(format!(
+        "Restored the user '{}', who can log in again",
+        restored_user.username
+    )))
+}
+
+// failed logins are counted for the account and for the client
+fn lockout_subjects(
+    username: &str,
+    request: &HttpRequest,
+    config: &Config,
+) -> Vec<(String, i32)> {
+    let mut subjects = vec![(
+        account_subject(username),
+        config.lockout.max_failures_per_account,
+    )];
+    if let Some(address) = request.peer_addr() {
+        subjects.push((
+            client_subject(&address.ip().to_string()),
+            config.lockout.max_failures_per_client,
+        ));
+    }
+    subjects
+}
+
+fn ensure_not_locked(
+    subjects: &[(String, i32)],
+    pool: &web::Data<Pool>,
+) -> Result<(), CustomError> {
+    let subject_names: Vec<String> =
+        subjects.iter().map(|(name, _)| name.clone()).collect();
+    LoginAttempt::ensure_not_locked(&subject_names, pool)
+}
 
-    return Ok(HttpResponse::Ok().body(format!(
-        "We deleted the user'{}', the {} associated persons",
-        deleted_user.username, deleted_persons
-    )));
+fn record_failures(
+    subjects: &[(String, i32)],
+    error_status_code: u16,
+    config: &Config,
+    pool: &web::Data<Pool>,
+) -> Result<(), CustomError> {
+    // only wrong credentials, not a disabled account, a bad request or a server failure
+    if error_status_code != 401 {
+        return Ok(());
+    }
+    for (subject, max_failures) in subjects.iter() {
+        LoginAttempt::record_failure(subject, *max_failures, &config.lockout, pool)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,367 @@
+use crate::toolbox::errors::CustomError;
+use anyhow::{anyhow, bail, Context};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters,
+        RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair};
+use std::{collections::HashMap, env, fs, path::Path, str::FromStr};
+
+#[derive(Clone)]
+pub struct JwtKey {
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    // what the JWKS endpoint publishes, none for shared secrets
+    jwk: Option<Jwk>,
+}
+
+// The keys are identified by the `kid` header of the tokens.
+// Only one key signs new tokens, the others are still accepted for verification:
+// to rotate, add a new key, make it the signing key, and remove the old one
+// once the tokens it signed have expired.
+#[derive(Clone)]
+pub struct KeyStore {
+    signing_kid: String,
+    keys: HashMap<String, JwtKey>,
+}
+
+impl KeyStore {
+    // JWT_KEYS_DIR: a directory of `<kid>.key` files (HMAC secrets)
+    //               and `<kid>.pem` files (RSA or Ed25519 private keys)
+    // JWT_KEYS: comma-separated `<kid>=<base64 secret>` pairs
+    // JWT_SIGNING_KID: the key that signs, optional if there is only one key
+    // JWT_RSA_ALGORITHM: RS256 (default), RS384, RS512, PS256, PS384 or PS512
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+
+        let rsa_algorithm = match env::var("JWT_RSA_ALGORITHM") {
+            Ok(name) => Algorithm::from_str(&name)
+                .ok()
+                .filter(|algorithm| is_rsa(*algorithm))
+                .ok_or_else(|| anyhow!("'{}' is not an RSA signing algorithm", name))?,
+            Err(_) => Algorithm::RS256,
+        };
+
+        if let Ok(keys_dir) = env::var("JWT_KEYS_DIR") {
+            let entries = fs::read_dir(&keys_dir).with_context(|| {
+                format!("Could not read the JWT keys dir {}", keys_dir)
+            })?;
+            for entry in entries {
+                let path = entry?.path();
+                let kid = match path.file_stem() {
+                    Some(stem) => stem.to_string_lossy().to_string(),
+                    None => continue,
+                };
+                let key = match path.extension().and_then(|extension| extension.to_str())
+                {
+                    Some("key") => {
+                        let secret = fs::read(&path).with_context(|| {
+                            format!("Could not read the JWT key {:?}", path)
+                        })?;
+                        JwtKey::from_secret(&secret)
+                    }
+                    Some("pem") => JwtKey::from_pem_file(&kid, &path, rsa_algorithm)?,
+                    _ => continue,
+                };
+                keys.insert(kid, key);
+            }
+        }
+
+        if let Ok(secrets) = env::var("JWT_KEYS") {
+            for pair in secrets
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+            {
+                let (kid, encoded_secret) = match pair.find('=') {
+                    Some(index) => (&pair[..index], &pair[index + 1..]),
+                    None => {
+                        bail!("JWT_KEYS entries should look like <kid>=<base64 secret>")
+                    }
+                };
+                let secret = base64::decode(encoded_secret).with_context(|| {
+                    format!("The JWT key '{}' is not valid base64", kid)
+                })?;
+                keys.insert(kid.to_string(), JwtKey::from_secret(&secret));
+            }
+        }
+
+        if keys.is_empty() {
+            bail!("No JWT key provided, set JWT_KEYS_DIR or JWT_KEYS in env");
+        }
+
+        let signing_kid = match env::var("JWT_SIGNING_KID") {
+            Ok(kid) => kid,
+            Err(_) if keys.len() == 1 => keys.keys().next().unwrap().clone(),
+            Err(_) => bail!(
+                "Several JWT keys provided, JWT_SIGNING_KID must tell which one signs"
+            ),
+        };
+        let signing_algorithm = match keys.get(&signing_kid) {
+            Some(key) => key.algorithm,
+            None => bail!(
+                "The JWT signing key '{}' is not among the provided keys",
+                signing_kid
+            ),
+        };
+
+        info!(
+            "Loaded {} JWT key(s), signing with '{}' ({:?})",
+            keys.len(),
+            signing_kid,
+            signing_algorithm
+        );
+        Ok(Self { signing_kid, keys })
+    }
+
+    // the keys of src/jwt/test_keys and an HMAC secret, as "rsa", "ed25519" and "hmac"
+    #[cfg(test)]
+    pub fn for_tests(signing_kid: &str) -> Self {
+        let test_keys =
+            Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/jwt/test_keys"));
+        let mut keys = HashMap::new();
+        for kid in &["rsa", "ed25519"] {
+            let path = test_keys.join(format!("{}.pem", kid));
+            let key = JwtKey::from_pem_file(kid, &path, Algorithm::RS256).unwrap();
+            keys.insert(kid.to_string(), key);
+        }
+        keys.insert("hmac".to_string(), JwtKey::from_secret(b"a test secret"));
+        Self {
+            signing_kid: signing_kid.to_string(),
+            keys,
+        }
+    }
+
+    pub fn signing_header_and_key(&self) -> (Header, &EncodingKey) {
+        let signing_key = &self.keys[&self.signing_kid];
+        let header = Header {
+            kid: Some(self.signing_kid.clone()),
+            ..Header::new(signing_key.algorithm)
+        };
+        (header, &signing_key.encoding_key)
+    }
+
+    // tokens issued before keys had ids are checked against the signing key
+    pub fn verification_key(&self, kid: Option<&str>) -> Result<&JwtKey, CustomError> {
+        let kid = kid.unwrap_or(&self.signing_kid);
+        match self.keys.get(kid) {
+            Some(key) => Ok(key),
+            None => Err(CustomError::new(
+                401,
+                format!("Unknown JWT key id '{}'", kid),
+            )),
+        }
+    }
+
+    // the public keys, for other services to verify our tokens
+    pub fn jwk_set(&self) -> JwkSet {
+        let mut keys: Vec<Jwk> = self
+            .keys
+            .values()
+            .filter_map(|key| key.jwk.clone())
+            .collect();
+        keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+        JwkSet { keys }
+    }
+}
+
+impl JwtKey {
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding_key
+    }
+
+    fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            jwk: None,
+        }
+    }
+
+    // the kind of key is found out from the PEM itself
+    fn from_pem_file(
+        kid: &str,
+        path: &Path,
+        rsa_algorithm: Algorithm,
+    ) -> anyhow::Result<Self> {
+        let pem_bytes = fs::read(path)
+            .with_context(|| format!("Could not read the JWT key {:?}", path))?;
+        let parsed_pem = pem::parse(&pem_bytes)
+            .with_context(|| format!("The JWT key {:?} is not valid PEM", path))?;
+        let der = parsed_pem.contents.as_slice();
+
+        if parsed_pem.tag == "PRIVATE KEY" {
+            if let Ok(key_pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+                let x = base64_url(key_pair.public_key().as_ref());
+                return Ok(Self {
+                    algorithm: Algorithm::EdDSA,
+                    encoding_key: EncodingKey::from_ed_der(der),
+                    decoding_key: DecodingKey::from_ed_der(
+                        key_pair.public_key().as_ref(),
+                    ),
+                    jwk: Some(Jwk {
+                        common: jwk_common_parameters(kid, Algorithm::EdDSA),
+                        algorithm: AlgorithmParameters::OctetKeyPair(
+                            OctetKeyPairParameters {
+                                key_type: OctetKeyPairType::OctetKeyPair,
+                                curve: EllipticCurve::Ed25519,
+                                x,
+                            },
+                        ),
+                    }),
+                });
+            }
+        }
+
+        let key_pair = match parsed_pem.tag.as_str() {
+            "RSA PRIVATE KEY" => RsaKeyPair::from_der(der),
+            "PRIVATE KEY" => RsaKeyPair::from_pkcs8(der),
+            tag => bail!("The JWT key {:?} is a '{}', not a private key", path, tag),
+        }
+        .map_err(|rejection| {
+            anyhow!("The JWT key {:?} was rejected: {}", path, rejection)
+        })?;
+
+        let modulus = key_pair.public_key().modulus();
+        let exponent = key_pair.public_key().exponent();
+        let modulus = modulus.big_endian_without_leading_zero();
+        let exponent = exponent.big_endian_without_leading_zero();
+        Ok(Self {
+            algorithm: rsa_algorithm,
+            encoding_key: EncodingKey::from_rsa_pem(&pem_bytes)?,
+            decoding_key: DecodingKey::from_rsa_raw_components(modulus, exponent),
+            jwk: Some(Jwk {
+                common: jwk_common_parameters(kid, rsa_algorithm),
+                algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: base64_url(modulus),
+                    e: base64_url(exponent),
+                }),
+            }),
+        })
+    }
+}
+
+fn jwk_common_parameters(kid: &str, algorithm: Algorithm) -> CommonParameters {
+    CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        algorithm: Some(algorithm),
+        key_id: Some(kid.to_string()),
+        ..CommonParameters::default()
+    }
+}
+
+fn is_rsa(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512
+    )
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::Validation;
+    use serde_json::{json, Value};
+
+    // signed by the signing key, checked with the key its kid names
+    fn round_trip(keys: &KeyStore) -> Result<Value, CustomError> {
+        let (header, encoding_key) = keys.signing_header_and_key();
+        let claims = json!({ "sub": "1", "exp": 4102444800i64 });
+        let token = jsonwebtoken::encode(&header, &claims, encoding_key)?;
+        let kid = jsonwebtoken::decode_header(&token)?.kid;
+        let key = keys.verification_key(kid.as_deref())?;
+        let decoded = jsonwebtoken::decode::<Value>(
+            &token,
+            key.decoding_key(),
+            &Validation::new(key.algorithm),
+        )?;
+        Ok(decoded.claims)
+    }
+
+    #[test]
+    fn rsa_and_ed25519_keys_sign_and_verify() {
+        for kid in &["rsa", "ed25519", "hmac"] {
+            let claims = round_trip(&KeyStore::for_tests(kid)).unwrap();
+            assert_eq!(claims["sub"], "1");
+        }
+        assert_eq!(
+            KeyStore::for_tests("rsa").keys["rsa"].algorithm,
+            Algorithm::RS256
+        );
+        assert_eq!(
+            KeyStore::for_tests("rsa").keys["ed25519"].algorithm,
+            Algorithm::EdDSA
+        );
+    }
+
+    #[test]
+    fn unknown_key_ids_are_refused() {
+        let keys = KeyStore::for_tests("rsa");
+        let refused = keys.verification_key(Some("retired")).err().unwrap();
+        assert_eq!(refused.error_status_code, 401);
+        // a token without kid is checked against the signing key
+        assert_eq!(
+            keys.verification_key(None).unwrap().algorithm,
+            Algorithm::RS256
+        );
+    }
+
+    #[test]
+    fn the_jwk_set_has_the_public_keys_only() {
+        let keys = KeyStore::for_tests("hmac");
+        let jwk_set = keys.jwk_set();
+        let kids: Vec<_> = jwk_set
+            .keys
+            .iter()
+            .map(|jwk| jwk.common.key_id.as_deref().unwrap())
+            .collect();
+        assert_eq!(kids, vec!["ed25519", "rsa"]);
+
+        match &jwk_set.find("rsa").unwrap().algorithm {
+            AlgorithmParameters::RSA(rsa) => assert_eq!(rsa.e, "AQAB"), // 65537
+            _ => panic!("the rsa key is published as another kind of key"),
+        }
+        match &jwk_set.find("ed25519").unwrap().algorithm {
+            // `openssl pkey -pubout` of test_keys/ed25519.pem
+            AlgorithmParameters::OctetKeyPair(ed25519) => {
+                assert_eq!(ed25519.x, "LDFkI9AtM-QE9tEBzWyX05t0vfV5NSVvew_uNmG0AcE")
+            }
+            _ => panic!("the ed25519 key is published as another kind of key"),
+        }
+
+        // other services verify our tokens with what is published
+        for kid in &["rsa", "ed25519"] {
+            let keys = KeyStore::for_tests(kid);
+            let (header, encoding_key) = keys.signing_header_and_key();
+            let token =
+                jsonwebtoken::encode(&header, &json!({ "sub": "1" }), encoding_key)
+                    .unwrap();
+            let jwk = jwk_set.find(kid).unwrap();
+            let mut validation = Validation::new(keys.keys[*kid].algorithm);
+            validation.required_spec_claims.clear();
+            validation.validate_exp = false;
+            assert!(jsonwebtoken::decode::<Value>(
+                &token,
+                &DecodingKey::from_jwk(jwk).unwrap(),
+                &validation
+            )
+            .is_ok());
+        }
+    }
+}
@@ -1,33 +1,119 @@
-use crate::{models::user::User, toolbox::errors::CustomError};
+pub mod keys;
+
+use crate::{
+    config::{env_or, Config},
+    models::user::User,
+    toolbox::errors::CustomError,
+};
 use chrono::Utc;
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
+use jsonwebtoken::Validation;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
+
+static CHALLENGE_PURPOSE: &str = "2fa";
+// checked by decode, whatever the token says
+static REQUIRED_CLAIMS: [&str; 5] = ["exp", "nbf", "iss", "aud", "sub"];
 
-pub static KEY: [u8; 16] = *include_bytes!("../secret.key");
-static ONE_WEEK: i64 = 60 * 60 * 24 * 7; // seconds
+// who the tokens are from and for, and how long they last
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub access_token_lifetime: i64,    // seconds
+    pub refresh_token_lifetime: i64,   // seconds, and of the sessions
+    pub challenge_token_lifetime: i64, // seconds
+    pub leeway: u64,                   // seconds, for the clocks of other servers
+}
+
+impl TokenConfig {
+    // JWT_ISSUER: PUBLIC_URL by default
+    // JWT_AUDIENCE: "ages_api" by default, tell the deployments apart with it
+    // JWT_ACCESS_TOKEN_LIFETIME_SECONDS: 900 by default
+    // JWT_REFRESH_TOKEN_LIFETIME_SECONDS: 2592000 (30 days) by default
+    // JWT_CHALLENGE_TOKEN_LIFETIME_SECONDS: 300 by default
+    // JWT_LEEWAY_SECONDS: 60 by default
+    pub fn from_env(public_url: &str) -> anyhow::Result<Self> {
+        let token_config = Self {
+            issuer: env_or("JWT_ISSUER", public_url.to_string())?,
+            audience: env_or("JWT_AUDIENCE", "ages_api".to_string())?,
+            access_token_lifetime: env_or("JWT_ACCESS_TOKEN_LIFETIME_SECONDS", 60 * 15)?,
+            refresh_token_lifetime: env_or(
+                "JWT_REFRESH_TOKEN_LIFETIME_SECONDS",
+                60 * 60 * 24 * 30,
+            )?,
+            challenge_token_lifetime: env_or(
+                "JWT_CHALLENGE_TOKEN_LIFETIME_SECONDS",
+                60 * 5,
+            )?,
+            leeway: env_or("JWT_LEEWAY_SECONDS", 60)?,
+        };
+        if token_config.access_token_lifetime <= 0
+            || token_config.refresh_token_lifetime <= 0
+            || token_config.challenge_token_lifetime <= 0
+        {
+            anyhow::bail!("The JWT lifetimes have to be positive");
+        }
+        Ok(token_config)
+    }
+}
 
 // This is to be used within the API
 #[derive(Serialize, Deserialize)]
 pub struct UserToken {
-    pub iat: i64, // issued at (posix)
-    pub exp: i64, // expires at (posix)
+    pub iss: String, // issuer, our JWT_ISSUER
+    pub aud: String, // audience, our JWT_AUDIENCE
+    pub sub: String, // subject, the user id for other services
+    pub iat: i64,    // issued at (posix)
+    pub nbf: i64,    // not before (posix)
+    pub exp: i64,    // expires at (posix)
     pub username: String,
-    pub uid: i32, // user id
+    pub uid: i32,    // user id
+    pub jti: String, // token id, to revoke it on logout
+    pub ver: i32,    // the user's token version, bumped to revoke all their tokens
+    pub sid: i32,    // the session of the login, to revoke it from another device
 }
 
 impl UserToken {
-    pub fn decode_from_string(token: String) -> Result<Self, CustomError> {
-        let token_data = jsonwebtoken::decode::<UserToken>(
-            &token,
-            &DecodingKey::from_secret(&KEY),
-            &Validation::default(),
-        )?;
-        Ok(token_data.claims)
+    pub fn decode_from_string(
+        token: String,
+        config: &Config,
+    ) -> Result<Self, CustomError> {
+        let user_token: Self = decode(&token, config)?;
+        if user_token.sub != user_token.uid.to_string() {
+            return Err(CustomError::new(
+                401,
+                "The token's subject is wrong".to_string(),
+            ));
+        }
+        Ok(user_token)
     }
+}
 
-    pub fn is_still_valid(&self) -> bool {
-        let now = Utc::now().timestamp_millis() / 1000; //seconds
-        now < self.exp
+// Proves the password was right, while the second factor is still to come.
+// It lacks the jti claim of a UserToken, so it can't be used as one.
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeToken {
+    pub iss: String,
+    pub aud: String,
+    pub sub: String,
+    pub iat: i64,
+    pub nbf: i64,
+    pub exp: i64,
+    pub username: String,
+    pub uid: i32,
+    pub ver: i32,
+    pub purpose: String,
+}
+
+impl ChallengeToken {
+    pub fn decode_from_string(token: &str, config: &Config) -> Result<Self, CustomError> {
+        match decode::<ChallengeToken>(token, config) {
+            Ok(challenge) if challenge.purpose == CHALLENGE_PURPOSE => Ok(challenge),
+            _ => Err(CustomError::new(
+                401,
+                "Invalid or expired challenge token".to_string(),
+            )),
+        }
     }
 }
 
@@ -36,30 +122,183 @@ impl UserToken {
 pub struct TokenResponse {
     pub token: String,
     pub token_type: String, // usually "Bearer"
+    pub expires_in: i64,    // seconds
+    pub refresh_token: String,
 }
 
 impl TokenResponse {
-    pub fn new(token_string: String) -> Self {
+    pub fn new(token_string: String, expires_in: i64, refresh_token: String) -> Self {
         Self {
             token: token_string,
             token_type: "Bearer".to_string(),
+            expires_in,
+            refresh_token,
         }
     }
 }
 
-pub fn generate_token_response(user: &User) -> Result<TokenResponse, CustomError> {
+pub fn generate_token_response(
+    user: &User,
+    session_id: i32,
+    refresh_token: String,
+    config: &Config,
+) -> Result<TokenResponse, CustomError> {
     let now = Utc::now().timestamp_millis() / 1000; //seconds
+    let lifetime = config.tokens.access_token_lifetime;
     let payload = UserToken {
+        iss: config.tokens.issuer.clone(),
+        aud: config.tokens.audience.clone(),
+        sub: user.id.to_string(),
         iat: now,
-        exp: now + ONE_WEEK,
+        nbf: now,
+        exp: now + lifetime,
         username: user.username.to_string(),
         uid: user.id,
+        jti: Uuid::new_v4().to_string(),
+        ver: user.token_version,
+        sid: session_id,
     };
-    let jwt_string = jsonwebtoken::encode(
-        &Header::default(),
-        &payload,
-        &EncodingKey::from_secret(&KEY),
-    )?;
-    let token_response = TokenResponse::new(jwt_string);
+    let (header, encoding_key) = config.jwt_keys.signing_header_and_key();
+    let jwt_string = jsonwebtoken::encode(&header, &payload, encoding_key)?;
+    let token_response = TokenResponse::new(jwt_string, lifetime, refresh_token);
     Ok(token_response)
 }
+
+// a login of the user from the tests, its access token
+#[cfg(test)]
+pub fn access_token_for_tests(
+    user: &User,
+    pool: &actix_web::web::Data<crate::config::db::Pool>,
+    config: &Config,
+) -> String {
+    use crate::models::session::Session;
+
+    let (session, refresh_token) = Session::start(
+        user.id,
+        Default::default(),
+        config.tokens.refresh_token_lifetime,
+        pool,
+    )
+    .unwrap();
+    generate_token_response(user, session.id, refresh_token, config)
+        .unwrap()
+        .token
+}
+
+// sent instead of a TokenResponse when the user has two-factor authentication on
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub challenge_token: String,
+    pub expires_in: i64, // seconds
+}
+
+pub fn generate_challenge_response(
+    user: &User,
+    config: &Config,
+) -> Result<ChallengeResponse, CustomError> {
+    let now = Utc::now().timestamp_millis() / 1000; //seconds
+    let lifetime = config.tokens.challenge_token_lifetime;
+    let payload = ChallengeToken {
+        iss: config.tokens.issuer.clone(),
+        aud: config.tokens.audience.clone(),
+        sub: user.id.to_string(),
+        iat: now,
+        nbf: now,
+        exp: now + lifetime,
+        username: user.username.to_string(),
+        uid: user.id,
+        ver: user.token_version,
+        purpose: CHALLENGE_PURPOSE.to_string(),
+    };
+    let (header, encoding_key) = config.jwt_keys.signing_header_and_key();
+    Ok(ChallengeResponse {
+        challenge_token: jsonwebtoken::encode(&header, &payload, encoding_key)?,
+        expires_in: lifetime,
+    })
+}
+
+// the algorithm comes from our key, never from the token header
+// a token of another deployment, even signed with the same key, has another issuer
+// or audience
+fn decode<T: DeserializeOwned>(token: &str, config: &Config) -> Result<T, CustomError> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let key = config.jwt_keys.verification_key(header.kid.as_deref())?;
+    let token_data = jsonwebtoken::decode::<T>(
+        token,
+        key.decoding_key(),
+        &validation(key.algorithm, &config.tokens),
+    )?;
+    Ok(token_data.claims)
+}
+
+fn validation(algorithm: jsonwebtoken::Algorithm, tokens: &TokenConfig) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[&tokens.issuer]);
+    validation.set_audience(&[&tokens.audience]);
+    validation.set_required_spec_claims(&REQUIRED_CLAIMS);
+    validation.validate_nbf = true;
+    validation.leeway = tokens.leeway;
+    validation
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    fn check(claims: Value) -> bool {
+        let tokens = TokenConfig {
+            issuer: "https://ages.example".to_string(),
+            audience: "ages_api".to_string(),
+            access_token_lifetime: 900,
+            refresh_token_lifetime: 60 * 60 * 24 * 30,
+            challenge_token_lifetime: 300,
+            leeway: 60,
+        };
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"k"),
+        )
+        .unwrap();
+        jsonwebtoken::decode::<Value>(
+            &token,
+            &DecodingKey::from_secret(b"k"),
+            &validation(Algorithm::HS256, &tokens),
+        )
+        .is_ok()
+    }
+
+    #[test]
+    fn tokens_of_other_deployments_are_refused() {
+        let now = Utc::now().timestamp();
+        let claims = |iss: &str, aud: &str, nbf: i64, exp: i64| {
+            json!({
+                "iss": iss,
+                "aud": aud,
+                "sub": "1",
+                "nbf": nbf,
+                "exp": exp,
+            })
+        };
+        let ours = "https://ages.example";
+        assert!(check(claims(ours, "ages_api", now, now + 900)));
+        assert!(!check(claims(ours, "ages_api_staging", now, now + 900)));
+        assert!(!check(claims(
+            "https://elsewhere",
+            "ages_api",
+            now,
+            now + 900
+        )));
+        // the clocks of other servers may be a bit off
+        assert!(check(claims(ours, "ages_api", now + 30, now + 900)));
+        assert!(!check(claims(ours, "ages_api", now + 600, now + 900)));
+        assert!(check(claims(ours, "ages_api", now - 900, now - 30)));
+        assert!(!check(claims(ours, "ages_api", now - 900, now - 600)));
+        // no claim can be left out
+        assert!(!check(
+            json!({ "iss": ours, "aud": "ages_api", "exp": now + 900 })
+        ));
+    }
+}
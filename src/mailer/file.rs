@@ -0,0 +1,57 @@
+use crate::{
+    mailer::{Email, Mailer},
+    toolbox::errors::CustomError,
+};
+use anyhow::Context;
+use chrono::Utc;
+use std::{env, fs, path::PathBuf};
+use uuid::Uuid;
+
+// for development: emails are logged, and written to MAIL_DIR if asked to
+pub struct FileMailer {
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn log_only() -> Self {
+        Self { dir: None }
+    }
+
+    // MAIL_DIR: where to write the emails, one file each
+    pub fn from_env() -> anyhow::Result<Self> {
+        let dir = PathBuf::from(
+            env::var("MAIL_DIR")
+                .context("MAILER is 'file' but MAIL_DIR is not set in env")?,
+        );
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create the mail dir {:?}", dir))?;
+        Ok(Self { dir: Some(dir) })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), CustomError> {
+        let content = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        match &self.dir {
+            Some(dir) => {
+                let path = dir.join(format!(
+                    "{}-{}.eml",
+                    Utc::now().format("%Y%m%d%H%M%S"),
+                    Uuid::new_v4()
+                ));
+                fs::write(&path, content).map_err(|error| {
+                    CustomError::new(
+                        500,
+                        format!("Could not write {:?}: {}", path, error),
+                    )
+                })?;
+                info!("Wrote an email to {} in {:?}", email.to, path);
+            }
+            None => info!("Email not sent, as MAILER is 'log':\n{}", content),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+pub mod file;
+pub mod smtp;
+
+use crate::toolbox::errors::CustomError;
+use anyhow::{bail, Context};
+use std::{env, sync::Arc};
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String, // plain text
+}
+
+// how emails leave the API
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), CustomError>;
+}
+
+// MAILER: "log", "file" or "smtp", see the implementations for their settings.
+// No default: the log mailer writes the reset tokens in the logs, a server
+// must not end up with it by forgetting the variable.
+pub fn from_env() -> anyhow::Result<Arc<dyn Mailer>> {
+    let mailer = env::var("MAILER").context("MAILER is not set in env")?;
+    let mailer: Arc<dyn Mailer> = match mailer.as_str() {
+        "log" => Arc::new(file::FileMailer::log_only()),
+        "file" => Arc::new(file::FileMailer::from_env()?),
+        "smtp" => Arc::new(smtp::SmtpMailer::from_env()?),
+        other => bail!("Unknown MAILER '{}', use log, file or smtp", other),
+    };
+    Ok(mailer)
+}
+
+// sent in the background: the response must not wait for the mail server,
+// nor tell by its delay whether an email was sent at all
+pub fn send_later(mailer: Arc<dyn Mailer>, email: Email) {
+    actix_rt::spawn(async move {
+        let to = email.to.clone();
+        if let Err(error) = actix_web::web::block(move || mailer.send(&email)).await {
+            error!("Could not send an email to {}: {}", to, error);
+        }
+    });
+}
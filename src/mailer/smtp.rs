@@ -0,0 +1,68 @@
+use crate::{
+    config::env_or,
+    mailer::{Email, Mailer},
+    toolbox::errors::CustomError,
+};
+use anyhow::{bail, Context};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message,
+    SmtpTransport, Transport,
+};
+use std::env;
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    // SMTP_HOST: the mail server, required
+    // SMTP_PORT: 25 by default
+    // SMTP_TLS: "none" (default, for a local catch-all server), "starttls" or "tls"
+    // SMTP_USERNAME and SMTP_PASSWORD: optional credentials
+    // MAIL_FROM: "ages_api <noreply@localhost>" by default
+    pub fn from_env() -> anyhow::Result<Self> {
+        let host =
+            env::var("SMTP_HOST").context("MAILER is 'smtp' but SMTP_HOST is not set")?;
+        let builder = match env::var("SMTP_TLS").as_deref() {
+            Ok("none") | Err(_) => SmtpTransport::builder_dangerous(&host),
+            Ok("starttls") => SmtpTransport::starttls_relay(&host)?,
+            Ok("tls") => SmtpTransport::relay(&host)?,
+            Ok(other) => bail!("Unknown SMTP_TLS '{}', use none, starttls or tls", other),
+        };
+        let mut builder = builder.port(env_or("SMTP_PORT", 25)?);
+        if let (Ok(username), Ok(password)) =
+            (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        let from = env_or("MAIL_FROM", "ages_api <noreply@localhost>".to_string())?;
+        let from = from
+            .parse()
+            .with_context(|| format!("MAIL_FROM is not a valid address: '{}'", from))?;
+
+        Ok(Self {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), CustomError> {
+        let to: Mailbox = email.to.parse().map_err(|error| {
+            CustomError::new(500, format!("Invalid recipient '{}': {}", email.to, error))
+        })?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .body(email.body.clone())
+            .map_err(|error| CustomError::new(500, error.to_string()))?;
+        self.transport
+            .send(&message)
+            .map_err(|error| CustomError::new(500, format!("SMTP error: {}", error)))?;
+        Ok(())
+    }
+}
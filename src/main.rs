@@ -5,23 +5,35 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate log;
 
+#[macro_use]
 pub mod config;
 pub mod controllers;
 pub mod jwt;
+pub mod mailer;
 pub mod middleware;
 pub mod models;
+pub mod oidc;
 pub mod schema;
 pub mod toolbox;
 
-use actix_cors::Cors;
-use actix_files::Files;
+use actix_cors::{Cors, CorsFactory};
 use actix_web::middleware::Logger;
-use actix_web::{http::header, App, HttpServer};
+use actix_web::{
+    http::header::{self, HeaderName},
+    web, App, HttpServer,
+};
 use anyhow::Context;
-use config::{db::migrate_and_config_db, routes::config_routes, Config};
+use config::{
+    db::{migrate_and_config_db, Pool},
+    routes::route_table,
+    Config, DeletionConfig,
+};
 use dotenv::dotenv;
 use env_logger;
 use middleware::authentication::Authentication;
+use models::user::User;
+use std::{env, time::Duration};
+use toolbox::user_cache::UserCache;
 
 #[actix_rt::main]
 async fn main() -> anyhow::Result<()> {
@@ -36,24 +48,41 @@ async fn main() -> anyhow::Result<()> {
     let pool = migrate_and_config_db(&config.database_url)
         .context("Failed to migrate and configure database")?;
 
+    // `ages_api grant-admin <username>` only gives the admin role, then exits
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("grant-admin") {
+        let name = args
+            .get(2)
+            .context("Usage: ages_api grant-admin <username>")?;
+        if !grant_admin(name, "command line", &pool)? {
+            anyhow::bail!("There is no user '{}'", name);
+        }
+        return Ok(());
+    }
+    for name in config.admin_usernames.iter() {
+        grant_admin(name, "ADMIN_USERNAMES", &pool)?;
+    }
+
+    let route_table = web::Data::new(route_table());
+    route_table
+        .check()
+        .context("The route table is inconsistent")?;
+
+    actix_rt::spawn(sweep_deleted_accounts(
+        pool.clone(),
+        config.account_deletion.clone(),
+        config.user_cache.clone(),
+    ));
+
     HttpServer::new(move || {
         App::new()
-            .wrap(
-                Cors::new()
-                    .send_wildcard()
-                    .allowed_origin(&cloned_config.allowed_origin)
-                    .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
-                    .allowed_methods(&cloned_config.allowed_methods)
-                    .allowed_header(header::CONTENT_TYPE)
-                    .finish(),
-            )
+            .wrap(cors(&cloned_config))
             .data(pool.clone())
+            .data(cloned_config.clone())
+            .app_data(route_table.clone())
             .wrap(Logger::default())
             .wrap(Authentication)
-            .configure(config_routes)
-            .service(
-                Files::new("/documentation", "./openapi").index_file("apicontract.json"),
-            )
+            .configure(|cfg| route_table.register(cfg))
     })
     .bind(&config.bind_url)?
     .run()
@@ -61,3 +90,62 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// The browser only sends the session cookies along to an allowed origin that takes
+// credentials, which excludes the wildcard.
+fn cors(config: &Config) -> CorsFactory {
+    let cors = Cors::new()
+        .allowed_origin(&config.allowed_origin)
+        .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
+        .allowed_methods(&config.allowed_methods)
+        .allowed_header(header::CONTENT_TYPE);
+    match config.session_cookies {
+        Some(_) => cors
+            .allowed_header(HeaderName::from_static("x-csrf-token"))
+            .supports_credentials()
+            .finish(),
+        None => cors.send_wildcard().finish(),
+    }
+}
+
+// false if there is no such user
+fn grant_admin(name: &str, granted_by: &str, pool: &Pool) -> anyhow::Result<bool> {
+    match User::grant_admin(name, granted_by, &web::Data::new(pool.clone()))
+        .map_err(|error| anyhow::anyhow!("Could not grant the admin role: {}", error))?
+    {
+        Some(user) => {
+            info!("'{}' is an administrator", user.username);
+            Ok(true)
+        }
+        None => {
+            warn!("Could not grant the admin role to '{}': no such user", name);
+            Ok(false)
+        }
+    }
+}
+
+// Purges the accounts whose grace period is over. Every instance sweeps, the row locks
+// keep them from purging the same account twice. The purged accounts leave the user
+// cache, or their tokens would still pass until the entries expire.
+async fn sweep_deleted_accounts(
+    pool: Pool,
+    account_deletion: DeletionConfig,
+    user_cache: UserCache,
+) {
+    let mut interval =
+        actix_rt::time::interval(Duration::from_secs(account_deletion.sweep_interval));
+    loop {
+        interval.tick().await;
+        let pool = web::Data::new(pool.clone());
+        let grace_period = account_deletion.grace_period;
+        match web::block(move || User::purge_deleted(grace_period, &pool)).await {
+            Ok(summaries) => {
+                for summary in summaries {
+                    user_cache.invalidate(summary.user_id);
+                    info!("Purged a deleted account: {:?}", summary);
+                }
+            }
+            Err(error) => warn!("Could not purge the deleted accounts: {}", error),
+        }
+    }
+}
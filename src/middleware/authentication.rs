@@ -1,18 +1,33 @@
 use crate::{
     config::db::Pool,
-    config::routes,
+    config::routes::{Policy, RouteTable},
+    config::Config,
     jwt::UserToken,
-    models::user::User,
+    models::{
+        personal_access_token::{PersonalAccessToken, TOKEN_PREFIX},
+        revoked_token::RevokedToken,
+        session::Session,
+        user::User,
+    },
+    toolbox::{
+        authenticated_user::{AuthenticatedUser, Credentials},
+        errors::CustomError,
+        session_cookies::{check_csrf, needs_csrf, SESSION_COOKIE},
+        user_cache::UserCache,
+    },
 };
 use actix_service::{Service, Transform};
+use chrono::{TimeZone, Utc};
+use diesel::result::Error as DieselError;
 
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
+    error::BlockingError,
     http::{
         header::{HeaderName, HeaderValue},
         Method,
     },
-    Error, HttpResponse,
+    web, Error, HttpMessage, HttpResponse, ResponseError,
 };
 
 use futures::{
@@ -21,7 +36,9 @@ use futures::{
 };
 
 use std::{
+    cell::RefCell,
     pin::Pin,
+    rc::Rc,
     task::{Context, Poll},
 };
 
@@ -29,7 +46,8 @@ pub struct Authentication;
 
 impl<S, B> Transform<S> for Authentication
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>
+        + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -41,17 +59,20 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthenticationMiddleware { service })
+        ok(AuthenticationMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
     }
 }
 
 pub struct AuthenticationMiddleware<S> {
-    service: S,
+    service: Rc<RefCell<S>>,
 }
 
 impl<S, B> Service for AuthenticationMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>
+        + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -61,7 +82,7 @@ where
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
     fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(ctx)
+        self.service.borrow_mut().poll_ready(ctx)
     }
 
     fn call(&mut self, mut request: ServiceRequest) -> Self::Future {
@@ -75,132 +96,483 @@ where
         );
         if Method::OPTIONS == *request.method() {
             debug!("The request verb is OPTIONS! It's a pass.");
-            let future = self.service.call(request);
+            let future = self.service.borrow_mut().call(request);
             return Box::pin(async move {
                 let response = future.await?;
                 Ok(response)
             });
         }
 
-        // allow /auth/login and /auth/signup
-        for ignore_route in routes::IGNORE_ROUTES.iter() {
-            if request.path().starts_with(ignore_route) {
-                debug!("The request path is in the ignored routes! It's a pass.");
-                let future = self.service.call(request);
-                return Box::pin(async move {
-                    let response = future.await?;
-                    Ok(response)
-                });
-            }
+        // the policy declared along with the route
+        let route_table = request.app_data::<RouteTable>().unwrap();
+        let policy = route_table.policy_for(request.method(), request.path());
+        if policy == Policy::Public {
+            debug!("The route is public! It's a pass.");
+            let future = self.service.borrow_mut().call(request);
+            return Box::pin(async move {
+                let response = future.await?;
+                Ok(response)
+            });
         }
 
-        debug!("Finding the authorization header...");
-        let authen_header = match request.headers_mut().get("Authorization") {
-            Some(authen_header) => authen_header,
-            None => {
-                return Box::pin(async move {
-                    Ok(request.into_response(
-                        HttpResponse::Unauthorized()
-                            .body("We did not find an authentication header.")
-                            .into_body(),
-                    ))
-                });
+        let config = request.app_data::<Config>().unwrap();
+
+        // browser clients in the session cookies mode send their JWT in a cookie instead
+        let session_cookie = match &config.session_cookies {
+            Some(_) if !request.headers().contains_key("Authorization") => {
+                request.cookie(SESSION_COOKIE)
             }
+            _ => None,
         };
+        let raw_token = if let Some(session_cookie) = session_cookie {
+            // the browser sends the cookie along with requests forged by other sites too
+            if needs_csrf(request.method()) {
+                if let Err(error) = check_csrf(&request) {
+                    return Box::pin(async move {
+                        Ok(request.into_response(error.error_response().into_body()))
+                    });
+                }
+            }
+            session_cookie.value().to_string()
+        } else {
+            debug!("Finding the authorization header...");
+            let authen_header = match request.headers_mut().get("Authorization") {
+                Some(authen_header) => authen_header,
+                None => {
+                    return Box::pin(async move {
+                        Ok(request.into_response(
+                            HttpResponse::Unauthorized()
+                                .body("We did not find an authentication header.")
+                                .into_body(),
+                        ))
+                    });
+                }
+            };
+
+            debug!("Parsing authorization header...");
+            let str_authen_header = match authen_header.to_str() {
+                Ok(str) => str,
+                Err(_) => {
+                    return Box::pin(async move {
+                        Ok(request.into_response(
+                            HttpResponse::Unauthorized()
+                                .body(
+                                    "The authorization header doesn't seem to be stringifyable"
+                                )
+                                .into_body(),
+                        ))
+                    });
+                }
+            };
 
-        debug!("Parsing authorization header...");
-        let str_authen_header = match authen_header.to_str() {
-            Ok(str) => str,
-            Err(_) => {
+            debug!(
+                "Checking the start of the authorization header: {}",
+                str_authen_header
+            );
+            if !str_authen_header.starts_with("Bearer")
+                && !str_authen_header.starts_with("bearer")
+            {
                 return Box::pin(async move {
                     Ok(request.into_response(
                         HttpResponse::Unauthorized()
-                            .body(
-                                "The authorization header doesn't seem to be stringifyable"
-                            )
+                            .body("The authorization header doesn't start with 'bearer'")
                             .into_body(),
                     ))
                 });
             }
+
+            debug!("Parsing token");
+            str_authen_header[6..str_authen_header.len()]
+                .trim()
+                .to_string()
         };
 
-        debug!(
-            "Checking the start of the authorization header: {}",
-            str_authen_header
-        );
-        if !str_authen_header.starts_with("Bearer")
-            && !str_authen_header.starts_with("bearer")
-        {
-            return Box::pin(async move {
-                Ok(request.into_response(
-                    HttpResponse::Unauthorized()
-                        .body("The authorization header doesn't start with 'bearer'")
-                        .into_body(),
-                ))
-            });
-        }
+        let presented = if raw_token.starts_with(TOKEN_PREFIX) {
+            PresentedToken::PersonalAccessToken(raw_token)
+        } else {
+            debug!("Decoding the token");
+            let token = match UserToken::decode_from_string(raw_token, &config) {
+                Ok(decoded_data) => decoded_data,
+                Err(decode_error) => {
+                    return Box::pin(async move {
+                        Ok(request.into_response(
+                            HttpResponse::Unauthorized()
+                                .body(format!(
+                                    "Could not decode the token: {}",
+                                    decode_error
+                                ))
+                                .into_body(),
+                        ))
+                    });
+                }
+            };
+            PresentedToken::Session(token)
+        };
 
-        debug!("Parsing token");
-        let raw_token = str_authen_header[6..str_authen_header.len()].trim();
+        // the database is only reached from the thread pool, not to block the event loop
+        let pool = request.app_data::<Pool>().unwrap();
+        let user_cache = config.user_cache.clone();
+        let service = self.service.clone();
+        Box::pin(async move {
+            let found =
+                web::block(move || find_user(presented, &pool, &user_cache)).await;
+            let (authenticated_user, user) = match found {
+                Ok(found) => found,
+                Err(BlockingError::Error(error)) => {
+                    return Ok(request.into_response(error.error_response().into_body()));
+                }
+                Err(BlockingError::Canceled) => {
+                    return Ok(request.into_response(
+                        HttpResponse::ServiceUnavailable()
+                            .body("Could not check the credentials")
+                            .into_body(),
+                    ));
+                }
+            };
 
-        debug!("Decoding the token");
-        let token = match UserToken::decode_from_string(raw_token.to_string()) {
-            Ok(decoded_data) => decoded_data,
-            Err(decode_error) => {
-                return Box::pin(async move {
-                    Ok(request.into_response(
-                        HttpResponse::Unauthorized()
-                            .body(format!("Could not decode the token: {}", decode_error))
+            // managing the account takes a real login
+            if let Credentials::PersonalAccessToken { .. } =
+                authenticated_user.credentials
+            {
+                if let Policy::Authenticated | Policy::Admin = policy {
+                    return Ok(request.into_response(
+                        HttpResponse::Forbidden()
+                            .body("Personal access tokens can't be used on this route")
                             .into_body(),
-                    ))
-                });
+                    ));
+                }
             }
-        };
 
-        // needed to check that the user exists
-        debug!("Connecting to the database");
-        let pool = request.app_data::<Pool>().unwrap();
-        let conn = match pool.get() {
-            Ok(conn) => conn,
-            Err(_) => {
-                return Box::pin(async move {
-                    Ok(request.into_response(
-                        HttpResponse::Unauthorized()
-                            .body("Could not connect to the database")
-                            .into_body(),
-                    ))
-                });
+            if user.disabled_at.is_some() {
+                return Ok(request.into_response(
+                    HttpResponse::Forbidden()
+                        .body("This account is disabled")
+                        .into_body(),
+                ));
             }
-        };
 
-        debug!("Checking the user's existence");
-        if User::find_user_by_id(&token.uid, &conn).is_err() {
-            return Box::pin(async move {
-                Ok(request.into_response(
-                    HttpResponse::Unauthorized()
-                        .body("This user doesn't exist.")
+            if user.deletion_requested_at.is_some() {
+                return Ok(request.into_response(
+                    HttpResponse::Forbidden()
+                        .body("This account is to be deleted")
                         .into_body(),
-                ))
-            });
-        };
+                ));
+            }
 
-        if UserToken::is_still_valid(&token) {
-            debug!("The JWT token is still valid, it's a pass");
-            let future = self.service.call(request);
+            if policy == Policy::Admin && !user.is_admin() {
+                return Ok(request.into_response(
+                    HttpResponse::Forbidden()
+                        .body("Only administrators can use this route")
+                        .into_body(),
+                ));
+            }
 
-            return Box::pin(async move {
-                let response = future.await?;
-                Ok(response)
-            });
-        } else {
-            error!("invalid jwt");
-            return Box::pin(async move {
-                Ok(request.into_response(
-                    HttpResponse::Unauthorized()
-                        .body("The JWT token isn't valid anymore")
+            if let Policy::Scope(scope) = policy {
+                if let Err(error) = authenticated_user.require_scope(scope) {
+                    return Ok(request.into_response(error.error_response().into_body()));
+                }
+            }
+
+            // the scoped routes are the ones to the data
+            if config.require_email_verification
+                && !user.email_verified
+                && matches!(policy, Policy::Scope(_))
+            {
+                return Ok(request.into_response(
+                    HttpResponse::Forbidden()
+                        .body("Verify your email address to use this route")
                         .into_body(),
-                ))
-            });
+                ));
+            }
+
+            debug!("The user is authenticated, it's a pass");
+            request.extensions_mut().insert(authenticated_user);
+            let future = service.borrow_mut().call(request);
+            let response = future.await?;
+            Ok(response)
+        })
+    }
+}
+
+enum PresentedToken {
+    PersonalAccessToken(String),
+    Session(UserToken),
+}
+
+// the credentials may well be good, the client can try again
+fn unavailable(error: impl std::fmt::Display) -> CustomError {
+    error!("Could not check the credentials: {}", error);
+    CustomError::new(503, "Could not check the credentials".to_string())
+}
+
+// runs on the thread pool
+fn find_user(
+    presented: PresentedToken,
+    pool: &Pool,
+    user_cache: &UserCache,
+) -> Result<(AuthenticatedUser, User), CustomError> {
+    let unauthorized = |message: &str| CustomError::new(401, message.to_string());
+    let conn = pool.get().map_err(unavailable)?;
+
+    match presented {
+        PresentedToken::PersonalAccessToken(raw_token) => {
+            debug!("Checking the personal access token");
+            let (personal_access_token, user) =
+                match PersonalAccessToken::authenticate(&raw_token, &conn) {
+                    Ok(Some(found)) => found,
+                    Ok(None) => {
+                        return Err(unauthorized(
+                            "Invalid or expired personal access token",
+                        ))
+                    }
+                    Err(error) => return Err(unavailable(error)),
+                };
+            let authenticated_user = AuthenticatedUser {
+                uid: user.id,
+                username: user.username.clone(),
+                credentials: Credentials::PersonalAccessToken {
+                    id: personal_access_token.id,
+                    scopes: personal_access_token.granted_scopes(),
+                },
+            };
+            Ok((authenticated_user, user))
+        }
+        PresentedToken::Session(token) => {
+            debug!("Checking the user's existence");
+            let user = match user_cache.get(token.uid) {
+                Some(user) => user,
+                None => {
+                    let user = match User::find_user_by_id(&token.uid, &conn) {
+                        Ok(user) => user,
+                        Err(DieselError::NotFound) => {
+                            return Err(unauthorized("This user doesn't exist."))
+                        }
+                        Err(error) => return Err(unavailable(error)),
+                    };
+                    user_cache.insert(user.clone());
+                    user
+                }
+            };
+
+            debug!("Checking that the token hasn't been revoked");
+            if token.ver != user.token_version
+                || RevokedToken::is_revoked(&token.jti, &conn).map_err(unavailable)?
+            {
+                return Err(unauthorized("This token has been revoked"));
+            }
+            if !Session::check(token.sid, user.id, &conn).map_err(unavailable)? {
+                return Err(unauthorized("This session has been revoked"));
+            }
+
+            let authenticated_user = AuthenticatedUser {
+                uid: user.id,
+                username: user.username.clone(),
+                credentials: Credentials::Session {
+                    jti: token.jti,
+                    expires_at: Utc.timestamp(token.exp, 0),
+                    session_id: token.sid,
+                },
+            };
+            Ok((authenticated_user, user))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::{db, Config},
+        jwt::{access_token_for_tests, UserToken},
+        models::{
+            personal_access_token::{
+                PersonalAccessToken, ReceivedPersonalAccessToken, Scope,
+            },
+            revoked_token::RevokedToken,
+            session::Session,
+            user::User,
+        },
+        toolbox::user_cache::UserCache,
+    };
+    use actix_web::{
+        http::StatusCode,
+        test::{self, TestRequest},
+    };
+    use chrono::{Duration, Utc};
+
+    fn get_account(token: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/auth/account")
+            .header("Authorization", format!("Bearer {}", token))
+    }
+
+    #[actix_rt::test]
+    async fn revoked_tokens_are_refused() {
+        let (config, pool) = (Config::for_tests(), db::test_pool());
+        let mut app = test_app!(config, pool).await;
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let revoked = access_token_for_tests(&user, &pool, &config);
+        let other = access_token_for_tests(&user, &pool, &config);
+
+        let jti = UserToken::decode_from_string(revoked.clone(), &config)
+            .unwrap()
+            .jti;
+        RevokedToken::revoke(&jti, user.id, Utc::now() + Duration::minutes(15), &pool)
+            .unwrap();
+
+        let response =
+            test::call_service(&mut app, get_account(&revoked).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            test::read_body(response).await,
+            "This token has been revoked"
+        );
+        let response =
+            test::call_service(&mut app, get_account(&other).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn tokens_of_an_older_version_are_refused() {
+        let (config, pool) = (Config::for_tests(), db::test_pool());
+        let mut app = test_app!(config, pool).await;
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let token = access_token_for_tests(&user, &pool, &config);
+        let response =
+            test::call_service(&mut app, get_account(&token).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        User::revoke_all_tokens(user.id, &pool).unwrap();
+        let response =
+            test::call_service(&mut app, get_account(&token).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // refused for its version, before its session is looked at
+        assert_eq!(
+            test::read_body(response).await,
+            "This token has been revoked"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn tokens_of_a_revoked_session_are_refused() {
+        let (config, pool) = (Config::for_tests(), db::test_pool());
+        let mut app = test_app!(config, pool).await;
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let token = access_token_for_tests(&user, &pool, &config);
+        let other = access_token_for_tests(&user, &pool, &config);
+
+        let sid = UserToken::decode_from_string(token.clone(), &config)
+            .unwrap()
+            .sid;
+        Session::revoke(user.id, sid, &pool).unwrap();
+
+        let response =
+            test::call_service(&mut app, get_account(&token).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            test::read_body(response).await,
+            "This session has been revoked"
+        );
+        let response =
+            test::call_service(&mut app, get_account(&other).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn personal_access_tokens_are_held_to_their_scopes() {
+        let (config, pool) = (Config::for_tests(), db::test_pool());
+        let mut app = test_app!(config, pool).await;
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        User::grant_admin(&user.username, "tests", &pool).unwrap();
+        let read_only = PersonalAccessToken::create(
+            user.id,
+            ReceivedPersonalAccessToken {
+                name: "backup script".to_string(),
+                scopes: vec![Scope::PersonsRead],
+                expires_in_days: None,
+            },
+            &pool,
+        )
+        .unwrap()
+        .token;
+
+        let new_person = serde_json::json!({ "name": "Oncle Jim", "birthdate": 0 });
+        let expected = vec![
+            (TestRequest::get().uri("/persons"), StatusCode::OK),
+            (
+                TestRequest::post().uri("/persons").set_json(&new_person),
+                StatusCode::FORBIDDEN,
+            ),
+            // the account and the administration take a real login, whatever the scopes
+            (
+                TestRequest::get().uri("/auth/account"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                TestRequest::get().uri("/admin/users"),
+                StatusCode::FORBIDDEN,
+            ),
+        ];
+        for (request, status) in expected {
+            let request = request
+                .header("Authorization", format!("Bearer {}", read_only))
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn cached_users_are_refused_after_a_change() {
+        let (mut config, pool) = (Config::for_tests(), db::test_pool());
+        config.user_cache = UserCache::new(100, std::time::Duration::from_secs(60));
+        let mut app = test_app!(config, pool).await;
+        let conn = pool.get().unwrap();
+        let (logged_out, disabled, deleted, admin) = (
+            User::insert_for_tests(&conn),
+            User::insert_for_tests(&conn),
+            User::insert_for_tests(&conn),
+            User::insert_for_tests(&conn),
+        );
+        drop(conn);
+        User::grant_admin(&admin.username, "tests", &pool).unwrap();
+        let admin_token = access_token_for_tests(&admin, &pool, &config);
+        let disable_uri = format!("/admin/users/{}/disable", disabled.id);
+
+        let changes = vec![
+            (
+                &logged_out,
+                TestRequest::post().uri("/auth/logout/all"),
+                None,
+            ),
+            (
+                &disabled,
+                TestRequest::post().uri(&disable_uri),
+                Some(&admin_token),
+            ),
+            (&deleted, TestRequest::delete().uri("/auth/delete"), None),
+        ];
+        // each change bumps the token version, that the cached user would not have
+        for (user, change, changed_by) in changes {
+            let token = access_token_for_tests(user, &pool, &config);
+            for _ in 0..2 {
+                let response =
+                    test::call_service(&mut app, get_account(&token).to_request()).await;
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+            assert!(config.user_cache.get(user.id).is_some());
+
+            let change = change
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", changed_by.unwrap_or(&token)),
+                )
+                .to_request();
+            let response = test::call_service(&mut app, change).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let response =
+                test::call_service(&mut app, get_account(&token).to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
         }
     }
 }
@@ -0,0 +1,112 @@
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    config::db::{DbConnection, Pool},
+    schema::audit_log,
+    toolbox::{errors::CustomError, pagination::Page},
+};
+
+// what the administrators did, and what the server did on their behalf
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    GrantAdmin,
+    ListUsers,
+    ViewUser,
+    DisableUser,
+    EnableUser,
+    ForcePasswordReset,
+    RestoreUser,
+    ViewAuditLog,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::GrantAdmin => "grant_admin",
+            Action::ListUsers => "list_users",
+            Action::ViewUser => "view_user",
+            Action::DisableUser => "disable_user",
+            Action::EnableUser => "enable_user",
+            Action::ForcePasswordReset => "force_password_reset",
+            Action::RestoreUser => "restore_user",
+            Action::ViewAuditLog => "view_audit_log",
+        }
+    }
+}
+
+#[derive(Serialize, Identifiable, Queryable, Clone, Debug)]
+#[table_name = "audit_log"]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor_id: Option<i32>, // none for the server itself
+    pub action: String,
+    pub target_user_id: Option<i32>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "audit_log"]
+pub struct InsertableAuditEntry {
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub target_user_id: Option<i32>,
+    pub details: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditFilter {
+    pub user_id: Option<i32>, // the entries about this user only
+}
+
+impl AuditEntry {
+    // in the same transaction as the action, so that none goes unrecorded
+    pub fn record(
+        actor_id: Option<i32>,
+        action: Action,
+        target_user_id: Option<i32>,
+        details: serde_json::Value,
+        conn: &DbConnection,
+    ) -> QueryResult<()> {
+        diesel::insert_into(audit_log::table)
+            .values(InsertableAuditEntry {
+                actor_id,
+                action: action.as_str().to_string(),
+                target_user_id,
+                details,
+            })
+            .execute(conn)?;
+        Ok(())
+    }
+
+    // the latest first. Reading the trail is recorded in it too.
+    pub fn find(
+        actor_id: i32,
+        filter: &AuditFilter,
+        page: &Page,
+        pool: &web::Data<Pool>,
+    ) -> Result<Vec<AuditEntry>, CustomError> {
+        let conn = pool.get()?;
+        let mut query = audit_log::table
+            .order(audit_log::id.desc())
+            .limit(page.limit())
+            .offset(page.offset())
+            .into_boxed();
+        if let Some(uid) = filter.user_id {
+            query = query.filter(audit_log::target_user_id.eq(uid));
+        }
+        let entries = query.load(&conn)?;
+        Self::record(
+            Some(actor_id),
+            Action::ViewAuditLog,
+            filter.user_id,
+            json!({ "limit": page.limit(), "offset": page.offset() }),
+            &conn,
+        )?;
+        Ok(entries)
+    }
+}
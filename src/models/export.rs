@@ -0,0 +1,306 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    config::db::DbConnection,
+    models::{
+        audit_entry::AuditEntry, external_identity::ExternalIdentity, person::Person,
+        personal_access_token::PersonalAccessToken, session::Session, user::User,
+    },
+    schema::{audit_log, external_identities, personal_access_tokens, persons, sessions},
+    toolbox::errors::CustomError,
+};
+
+// what the document says it is, for the import to check
+pub static EXPORT_FORMAT: &str = "ages_api.export";
+pub static EXPORT_VERSION: i32 = 1;
+// rows read at a time from the tables that can grow large
+static BATCH_SIZE: i64 = 500;
+
+// the users row, without the password hash or the TOTP secret
+#[derive(Serialize, Debug)]
+pub struct ExportedAccount {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub password_reset_required: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedPerson {
+    pub id: i32,
+    pub name: String,
+    pub birthdate: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// The document is written one part after the other, so that the persons and the
+// audit entries are never all in memory at once.
+pub enum Part {
+    Head,
+    Persons { after: Option<i32> }, // the id of the last one written
+    AuditEntries { after: Option<i32> },
+    Done,
+}
+
+impl Part {
+    // the next chunk of the document, and the part that follows it
+    pub fn write(
+        self,
+        uid: i32,
+        current_session_id: Option<i32>,
+        conn: &DbConnection,
+    ) -> Result<(Vec<u8>, Part), CustomError> {
+        let mut chunk = Vec::new();
+        let next = match self {
+            Part::Head => {
+                let user = User::find_user_by_id(&uid, conn)?;
+                chunk.push(b'{');
+                field(&mut chunk, "format", &EXPORT_FORMAT)?;
+                field(&mut chunk, "version", &EXPORT_VERSION)?;
+                field(&mut chunk, "exported_at", &Utc::now())?;
+                field(&mut chunk, "account", &account(user))?;
+                let sessions = sessions::table
+                    .filter(sessions::user_id.eq(uid))
+                    .order(sessions::id)
+                    .load::<Session>(conn)?
+                    .into_iter()
+                    .map(|session| session.view(current_session_id))
+                    .collect::<Vec<_>>();
+                field(&mut chunk, "sessions", &sessions)?;
+                let personal_access_tokens = personal_access_tokens::table
+                    .filter(personal_access_tokens::user_id.eq(uid))
+                    .order(personal_access_tokens::id)
+                    .load::<PersonalAccessToken>(conn)?;
+                field(
+                    &mut chunk,
+                    "personal_access_tokens",
+                    &personal_access_tokens,
+                )?;
+                let external_identities = external_identities::table
+                    .filter(external_identities::user_id.eq(uid))
+                    .order(external_identities::id)
+                    .load::<ExternalIdentity>(conn)?
+                    .into_iter()
+                    .map(|identity| ExportedIdentity {
+                        issuer: identity.issuer,
+                        subject: identity.subject,
+                        created_at: identity.created_at,
+                    })
+                    .collect::<Vec<_>>();
+                field(&mut chunk, "external_identities", &external_identities)?;
+                chunk.extend_from_slice(b"\"persons\":[");
+                Part::Persons { after: None }
+            }
+            Part::Persons { after } => {
+                let batch = persons::table
+                    .filter(persons::user_id.eq(uid))
+                    .filter(persons::id.gt(after.unwrap_or(0)))
+                    .order(persons::id)
+                    .limit(BATCH_SIZE)
+                    .load::<Person>(conn)?;
+                match batch.last() {
+                    Some(last) => {
+                        let next = Part::Persons {
+                            after: Some(last.id),
+                        };
+                        let batch: Vec<ExportedPerson> = batch
+                            .into_iter()
+                            .map(|person| ExportedPerson {
+                                id: person.id,
+                                name: person.name,
+                                birthdate: person.birthdate,
+                            })
+                            .collect();
+                        elements(&mut chunk, &batch, after.is_none())?;
+                        next
+                    }
+                    None => {
+                        chunk.extend_from_slice(b"],\"audit_entries\":[");
+                        Part::AuditEntries { after: None }
+                    }
+                }
+            }
+            // what the user did, and what was done to their account
+            Part::AuditEntries { after } => {
+                let batch = audit_log::table
+                    .filter(
+                        audit_log::actor_id
+                            .eq(uid)
+                            .or(audit_log::target_user_id.eq(uid)),
+                    )
+                    .filter(audit_log::id.gt(after.unwrap_or(0)))
+                    .order(audit_log::id)
+                    .limit(BATCH_SIZE)
+                    .load::<AuditEntry>(conn)?;
+                match batch.last() {
+                    Some(last) => {
+                        let next = Part::AuditEntries {
+                            after: Some(last.id),
+                        };
+                        elements(&mut chunk, &batch, after.is_none())?;
+                        next
+                    }
+                    None => {
+                        chunk.extend_from_slice(b"]}");
+                        Part::Done
+                    }
+                }
+            }
+            Part::Done => Part::Done,
+        };
+        Ok((chunk, next))
+    }
+}
+
+fn account(user: User) -> ExportedAccount {
+    ExportedAccount {
+        id: user.id,
+        username: user.username,
+        email: user.email,
+        email_verified: user.email_verified,
+        totp_enabled: user.totp_enabled,
+        role: user.role,
+        disabled_at: user.disabled_at,
+        password_reset_required: user.password_reset_required,
+    }
+}
+
+// `"name":value,`
+fn field<T: Serialize>(
+    chunk: &mut Vec<u8>,
+    name: &str,
+    value: &T,
+) -> serde_json::Result<()> {
+    serde_json::to_writer(&mut *chunk, name)?;
+    chunk.push(b':');
+    serde_json::to_writer(&mut *chunk, value)?;
+    chunk.push(b',');
+    Ok(())
+}
+
+// the elements of an array, without the brackets, carrying on from the previous batch
+fn elements<T: Serialize>(
+    chunk: &mut Vec<u8>,
+    batch: &[T],
+    first_batch: bool,
+) -> serde_json::Result<()> {
+    for (index, element) in batch.iter().enumerate() {
+        if index > 0 || !first_batch {
+            chunk.push(b',');
+        }
+        serde_json::to_writer(&mut *chunk, element)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config::{db, Config},
+        jwt::access_token_for_tests,
+        models::{
+            person::InsertablePerson,
+            personal_access_token::{ReceivedPersonalAccessToken, Scope},
+        },
+        schema::users,
+    };
+    use actix_web::{
+        http::StatusCode,
+        test::{self, TestRequest},
+    };
+
+    #[test]
+    fn batches_make_one_array() {
+        let mut document = b"{".to_vec();
+        field(&mut document, "version", &EXPORT_VERSION).unwrap();
+        document.extend_from_slice(b"\"numbers\":[");
+        elements(&mut document, &[1, 2], true).unwrap();
+        elements(&mut document, &[3], false).unwrap();
+        document.extend_from_slice(b"],\"empty\":[");
+        document.extend_from_slice(b"]}");
+
+        let parsed: serde_json::Value = serde_json::from_slice(&document).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({ "version": 1, "numbers": [1, 2, 3], "empty": [] })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn the_whole_document_without_the_secrets() {
+        let (config, pool) = (Config::for_tests(), db::test_pool());
+        let mut app = test_app!(config, pool).await;
+        let conn = pool.get().unwrap();
+        let user = User::insert_for_tests(&conn);
+        let totp_secret = "JBSWY3DPEHPK3PXP";
+        diesel::update(users::table.find(user.id))
+            .set(users::totp_secret.eq(totp_secret))
+            .execute(&conn)
+            .unwrap();
+        // more than a batch
+        let count = BATCH_SIZE as usize + 1;
+        let new_persons: Vec<InsertablePerson> = (0..count)
+            .map(|index| InsertablePerson {
+                name: format!("Person {}", index),
+                birthdate: index as i64,
+                user_id: user.id,
+            })
+            .collect();
+        diesel::insert_into(persons::table)
+            .values(new_persons)
+            .execute(&conn)
+            .unwrap();
+        drop(conn);
+        let token = ReceivedPersonalAccessToken {
+            name: "backup script".to_string(),
+            scopes: vec![Scope::PersonsRead],
+            expires_in_days: None,
+        };
+        let token_hash = PersonalAccessToken::create(user.id, token, &pool)
+            .unwrap()
+            .personal_access_token
+            .token_hash;
+        let access_token = access_token_for_tests(&user, &pool, &config);
+
+        let request = TestRequest::get()
+            .uri("/auth/export")
+            .header("Authorization", format!("Bearer {}", access_token))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test::read_body(response).await;
+
+        let document: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(document["format"], EXPORT_FORMAT);
+        assert_eq!(document["version"], EXPORT_VERSION);
+        assert_eq!(document["account"]["id"], user.id);
+        let names: Vec<&str> = document["persons"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|person| person["name"].as_str().unwrap())
+            .collect();
+        let expected: Vec<String> = (0..count)
+            .map(|index| format!("Person {}", index))
+            .collect();
+        assert_eq!(names, expected);
+
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        for secret in &[&user.password, totp_secret, &token_hash, "token_hash"] {
+            assert!(!body.contains(secret), "the export contains {}", secret);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+
+use crate::{
+    config::db::{DbConnection, Pool},
+    models::user::User,
+    oidc::IdTokenClaims,
+    schema::{external_identities, users},
+    toolbox::{errors::CustomError, username},
+};
+
+// attempts at a free username for a new account, before giving up
+static USERNAME_ATTEMPTS: usize = 5;
+
+// an account of the identity provider, linked to one of ours
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "external_identities"]
+pub struct ExternalIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub issuer: String,
+    pub subject: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "external_identities"]
+pub struct InsertableExternalIdentity<'a> {
+    pub user_id: i32,
+    pub issuer: &'a str,
+    pub subject: &'a str,
+}
+
+impl ExternalIdentity {
+    // the account linked to this identity, created on its first login if allowed
+    pub fn sign_in(
+        claims: &IdTokenClaims,
+        create_accounts: bool,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            if let Some(user) = Self::find_user(claims, &conn)? {
+                return Ok(user);
+            }
+            if !create_accounts {
+                return Err(CustomError::new(
+                    403,
+                    "No account is linked to this identity, \
+                     link it from a logged in account"
+                        .to_string(),
+                ));
+            }
+            let user = create_user(claims, &conn)?;
+            Self::insert(user.id, claims, &conn)?;
+            info!(
+                "Created the account '{}' for {} of {}",
+                user.username, claims.sub, claims.iss
+            );
+            Ok(user)
+        })
+    }
+
+    // so that the account can log in with the provider too
+    pub fn link(
+        uid: i32,
+        claims: &IdTokenClaims,
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| match Self::find_user(claims, &conn)? {
+            Some(user) if user.id == uid => Ok(()),
+            Some(_) => Err(CustomError::new(
+                409,
+                "This identity is already linked to another account".to_string(),
+            )),
+            None => Self::insert(uid, claims, &conn),
+        })
+    }
+
+    fn find_user(
+        claims: &IdTokenClaims,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<User>> {
+        external_identities::table
+            .inner_join(users::table)
+            .filter(external_identities::issuer.eq(&claims.iss))
+            .filter(external_identities::subject.eq(&claims.sub))
+            .select(users::all_columns)
+            .first::<User>(conn)
+            .optional()
+    }
+
+    fn insert(
+        uid: i32,
+        claims: &IdTokenClaims,
+        conn: &DbConnection,
+    ) -> Result<(), CustomError> {
+        diesel::insert_into(external_identities::table)
+            .values(InsertableExternalIdentity {
+                user_id: uid,
+                issuer: &claims.iss,
+                subject: &claims.sub,
+            })
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+// Named after the identity, with a number when the name is taken.
+// It has no password: it logs in with the provider, or sets one with a password reset.
+fn create_user(claims: &IdTokenClaims, conn: &DbConnection) -> Result<User, CustomError> {
+    let wanted_username = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| {
+            claims
+                .email
+                .as_deref()
+                .and_then(|address| address.split('@').next())
+        })
+        .and_then(|name| username::normalize(name).ok())
+        .unwrap_or_else(|| "user".to_string());
+
+    for attempt in 0..USERNAME_ATTEMPTS {
+        let candidate = match attempt {
+            0 => wanted_username.clone(),
+            _ => format!(
+                "{}-{}",
+                wanted_username,
+                rand::thread_rng().gen_range(1000, 10000)
+            ),
+        };
+        // no error on a taken name, which would abort the transaction
+        let created = diesel::insert_into(users::table)
+            .values((users::username.eq(&candidate), users::password.eq("")))
+            .on_conflict_do_nothing()
+            .get_result::<User>(conn)
+            .optional()?;
+        if let Some(user) = created {
+            return adopt_email(user, claims, conn);
+        }
+    }
+    Err(CustomError::new(
+        409,
+        format!("Could not find a free username like '{}'", wanted_username),
+    ))
+}
+
+// the provider's address, if it verified it and no other account uses it
+fn adopt_email(
+    user: User,
+    claims: &IdTokenClaims,
+    conn: &DbConnection,
+) -> Result<User, CustomError> {
+    let address = match &claims.email {
+        Some(address) if claims.email_verified => address,
+        _ => return Ok(user),
+    };
+    match User::check_email(address, Some(user.id), conn) {
+        Ok(address) => Ok(diesel::update(&user)
+            .set((users::email.eq(address), users::email_verified.eq(true)))
+            .get_result(conn)?),
+        Err(_) => Ok(user),
+    }
+}
@@ -0,0 +1,297 @@
+use actix_web::web;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    config::db::Pool,
+    models::{
+        export::{EXPORT_FORMAT, EXPORT_VERSION},
+        person::{InsertablePerson, Person},
+    },
+    schema::{persons, users},
+    toolbox::errors::CustomError,
+};
+
+// What the import reads of an export document: only the persons are recreated.
+// The other parts belong to the account of the deployment it comes from.
+#[derive(Deserialize, Debug)]
+pub struct ImportDocument {
+    pub format: String,
+    pub version: i32,
+    pub persons: Vec<ImportedPerson>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportedPerson {
+    #[serde(default)]
+    pub id: Option<i32>, // in the deployment it comes from
+    pub name: String,
+    pub birthdate: i64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    #[default]
+    Merge, // adds the persons the user doesn't have yet
+    Replace, // deletes the user's persons first
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportOptions {
+    #[serde(default)]
+    pub mode: ImportMode,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Created,
+    Duplicate, // same name and birthdate as a person the user has, or one before it
+}
+
+// what became of one person of the document
+#[derive(Serialize, Debug)]
+pub struct RecordOutcome {
+    pub index: usize,           // in the persons of the document
+    pub source_id: Option<i32>, // its id in the document
+    pub outcome: Outcome,
+    pub person_id: i32, // the person created, or the one it duplicates
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImportReport {
+    pub mode: ImportMode,
+    pub deleted: usize, // by the replace mode
+    pub created: usize,
+    pub duplicates: usize,
+    pub records: Vec<RecordOutcome>,
+}
+
+impl ImportDocument {
+    pub fn check(&self) -> Result<(), CustomError> {
+        if self.format != EXPORT_FORMAT {
+            return Err(CustomError::new(
+                400,
+                format!("This is not a document of format '{}'", EXPORT_FORMAT),
+            ));
+        }
+        if self.version < 1 || self.version > EXPORT_VERSION {
+            return Err(CustomError::new(
+                400,
+                format!(
+                    "Version {} of the export can't be imported, at most {}",
+                    self.version, EXPORT_VERSION
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // all or nothing
+    pub fn import(
+        self,
+        uid: i32,
+        mode: ImportMode,
+        pool: &web::Data<Pool>,
+    ) -> Result<ImportReport, CustomError> {
+        self.check()?;
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            // two imports at once would miss each other's duplicates
+            users::table
+                .find(uid)
+                .select(users::id)
+                .for_update()
+                .first::<i32>(&conn)?;
+
+            let deleted = match mode {
+                ImportMode::Replace => {
+                    diesel::delete(persons::table.filter(persons::user_id.eq(uid)))
+                        .execute(&conn)?
+                }
+                ImportMode::Merge => 0,
+            };
+            let mut known: HashMap<(String, i64), i32> = persons::table
+                .filter(persons::user_id.eq(uid))
+                .load::<Person>(&conn)?
+                .into_iter()
+                .map(|person| ((person.name, person.birthdate), person.id))
+                .collect();
+
+            let mut records = Vec::with_capacity(self.persons.len());
+            for (index, imported) in self.persons.into_iter().enumerate() {
+                let key = (imported.name, imported.birthdate);
+                let (outcome, person_id) = match known.get(&key) {
+                    Some(person_id) => (Outcome::Duplicate, *person_id),
+                    None => {
+                        let person = diesel::insert_into(persons::table)
+                            .values(InsertablePerson {
+                                name: key.0.clone(),
+                                birthdate: key.1,
+                                user_id: uid,
+                            })
+                            .get_result::<Person>(&conn)?;
+                        known.insert(key, person.id);
+                        (Outcome::Created, person.id)
+                    }
+                };
+                records.push(RecordOutcome {
+                    index,
+                    source_id: imported.id,
+                    outcome,
+                    person_id,
+                });
+            }
+
+            let count = |outcome| records.iter().filter(|r| r.outcome == outcome).count();
+            Ok(ImportReport {
+                mode,
+                deleted,
+                created: count(Outcome::Created),
+                duplicates: count(Outcome::Duplicate),
+                records,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config::db,
+        models::{person::ReceivedPerson, user::User},
+    };
+
+    fn document(format: &str, version: i32) -> ImportDocument {
+        serde_json::from_value(serde_json::json!({
+            "format": format,
+            "version": version,
+            "exported_at": "2026-10-18T10:00:00Z",
+            "account": { "id": 1, "username": "someone" },
+            "persons": [{ "id": 3, "name": "Oncle Jim", "birthdate": -447399150 }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_known_versions() {
+        assert!(document(EXPORT_FORMAT, EXPORT_VERSION).check().is_ok());
+        assert!(document(EXPORT_FORMAT, EXPORT_VERSION + 1).check().is_err());
+        assert!(document(EXPORT_FORMAT, 0).check().is_err());
+        assert!(document("something.else", EXPORT_VERSION).check().is_err());
+    }
+
+    fn document_of(persons: &[(i32, &str, i64)]) -> ImportDocument {
+        ImportDocument {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            persons: persons
+                .iter()
+                .map(|(id, name, birthdate)| ImportedPerson {
+                    id: Some(*id),
+                    name: name.to_string(),
+                    birthdate: *birthdate,
+                })
+                .collect(),
+        }
+    }
+
+    fn add_person(uid: i32, name: &str, pool: &web::Data<Pool>) -> Person {
+        let person = ReceivedPerson {
+            name: name.to_string(),
+            birthdate: 0,
+        };
+        Person::create(uid, person, pool).unwrap()
+    }
+
+    fn names(uid: i32, pool: &web::Data<Pool>) -> Vec<String> {
+        let mut names: Vec<String> = Person::find_all(uid, pool)
+            .unwrap()
+            .into_iter()
+            .map(|person| person.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn merging_skips_the_duplicates() {
+        let pool = db::test_pool();
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let jim = add_person(user.id, "Oncle Jim", &pool);
+
+        let report = document_of(&[
+            (1, "Oncle Jim", 0), // already there
+            (2, "Tante Lou", 0),
+            (3, "Tante Lou", 0),  // twice in the document
+            (4, "Oncle Jim", 42), // another birthdate, another person
+        ])
+        .import(user.id, ImportMode::Merge, &pool)
+        .unwrap();
+
+        assert_eq!(
+            (report.deleted, report.created, report.duplicates),
+            (0, 2, 2)
+        );
+        let outcomes: Vec<(usize, Option<i32>, Outcome)> = report
+            .records
+            .iter()
+            .map(|record| (record.index, record.source_id, record.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (0, Some(1), Outcome::Duplicate),
+                (1, Some(2), Outcome::Created),
+                (2, Some(3), Outcome::Duplicate),
+                (3, Some(4), Outcome::Created),
+            ]
+        );
+        assert_eq!(report.records[0].person_id, jim.id);
+        assert_eq!(report.records[2].person_id, report.records[1].person_id);
+        assert_eq!(
+            names(user.id, &pool),
+            ["Oncle Jim", "Oncle Jim", "Tante Lou"]
+        );
+    }
+
+    #[test]
+    fn replacing_deletes_the_persons_first() {
+        let pool = db::test_pool();
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let jim = add_person(user.id, "Oncle Jim", &pool);
+        add_person(user.id, "Cousin Max", &pool);
+
+        let report = document_of(&[(1, "Oncle Jim", 0), (2, "Tante Lou", 0)])
+            .import(user.id, ImportMode::Replace, &pool)
+            .unwrap();
+
+        assert_eq!(
+            (report.deleted, report.created, report.duplicates),
+            (2, 2, 0)
+        );
+        // recreated, not kept
+        assert_ne!(report.records[0].person_id, jim.id);
+        assert_eq!(names(user.id, &pool), ["Oncle Jim", "Tante Lou"]);
+    }
+
+    #[test]
+    fn one_failed_insert_imports_nothing() {
+        let pool = db::test_pool();
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        add_person(user.id, "Oncle Jim", &pool);
+
+        // Postgres refuses the NUL character in a text
+        let failed = document_of(&[(1, "Tante Lou", 0), (2, "Cousin\0Max", 0)]).import(
+            user.id,
+            ImportMode::Replace,
+            &pool,
+        );
+
+        assert!(failed.is_err());
+        assert_eq!(names(user.id, &pool), ["Oncle Jim"]);
+    }
+}
@@ -0,0 +1,183 @@
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    config::{db::Pool, LockoutConfig},
+    schema::login_attempts,
+    toolbox::{errors::CustomError, username},
+};
+
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "login_attempts"]
+#[primary_key(subject)]
+pub struct LoginAttempt {
+    pub subject: String,
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "login_attempts"]
+pub struct InsertableLoginAttempt<'a> {
+    pub subject: &'a str,
+}
+
+// failed logins are counted both per account and per client,
+// so that neither guessing one password nor spraying many accounts goes unnoticed
+pub fn account_subject(username: &str) -> String {
+    format!("account:{}", username::fold(username))
+}
+
+pub fn client_subject(ip_address: &str) -> String {
+    format!("client:{}", ip_address)
+}
+
+impl LoginAttempt {
+    pub fn ensure_not_locked(
+        subjects: &[String],
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        let locked_until = login_attempts::table
+            .select(login_attempts::locked_until)
+            .filter(login_attempts::subject.eq_any(subjects))
+            .filter(login_attempts::locked_until.gt(Utc::now()))
+            .order(login_attempts::locked_until.desc())
+            .first::<Option<DateTime<Utc>>>(&conn)
+            .optional()?
+            .flatten();
+
+        match locked_until {
+            Some(locked_until) => {
+                let retry_after = (locked_until - Utc::now()).num_seconds() + 1;
+                Err(CustomError::new(
+                    429,
+                    "Too many failed logins, try again later".to_string(),
+                )
+                .with_retry_after(retry_after))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_failure(
+        subject: &str,
+        max_failures: i32,
+        lockout: &LockoutConfig,
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            diesel::insert_into(login_attempts::table)
+                .values(InsertableLoginAttempt { subject })
+                .on_conflict_do_nothing()
+                .execute(&conn)?;
+            let attempt = login_attempts::table
+                .find(subject)
+                .for_update()
+                .first::<LoginAttempt>(&conn)?;
+
+            let now = Utc::now();
+            let (failures, lockout_seconds) =
+                count_failure(&attempt, now, max_failures, lockout);
+            let locked_until = lockout_seconds.map(|seconds| {
+                warn!("Locking out {} for {} seconds", subject, seconds);
+                now + Duration::seconds(seconds)
+            });
+
+            diesel::update(&attempt)
+                .set((
+                    login_attempts::failures.eq(failures),
+                    login_attempts::last_failure_at.eq(now),
+                    login_attempts::locked_until.eq(locked_until),
+                ))
+                .execute(&conn)?;
+            Ok(())
+        })
+    }
+
+    // on successful login, or to unlock an account
+    pub fn clear(subject: &str, pool: &web::Data<Pool>) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        diesel::delete(login_attempts::table.find(subject)).execute(&conn)?;
+        Ok(())
+    }
+}
+
+// The failures of the subject once this one is counted, and for how many seconds
+// they lock it out. Past the limit, each failure locks the subject out twice as long
+// as the previous one.
+fn count_failure(
+    attempt: &LoginAttempt,
+    now: DateTime<Utc>,
+    max_failures: i32,
+    lockout: &LockoutConfig,
+) -> (i32, Option<i64>) {
+    let failures = if now - attempt.last_failure_at
+        > Duration::seconds(lockout.failures_forgotten_after)
+    {
+        1
+    } else {
+        attempt.failures + 1
+    };
+    if failures < max_failures {
+        return (failures, None);
+    }
+    let doublings = (failures - max_failures).min(30) as u32;
+    let seconds = lockout
+        .base_lockout
+        .saturating_mul(2i64.pow(doublings))
+        .min(lockout.max_lockout);
+    (failures, Some(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lockout() -> LockoutConfig {
+        LockoutConfig {
+            max_failures_per_account: 5,
+            max_failures_per_client: 20,
+            base_lockout: 30,
+            max_lockout: 60 * 60,
+            failures_forgotten_after: 60 * 60 * 24,
+        }
+    }
+
+    // the lockout of the next failure, after `failures` of them `ago` seconds ago
+    fn next(failures: i32, ago: i64) -> (i32, Option<i64>) {
+        let now = Utc::now();
+        let attempt = LoginAttempt {
+            subject: "account:someone".to_string(),
+            failures,
+            last_failure_at: now - Duration::seconds(ago),
+            locked_until: None,
+        };
+        count_failure(&attempt, now, 5, &lockout())
+    }
+
+    #[test]
+    fn the_lockout_doubles_with_each_failure_past_the_limit() {
+        assert_eq!(next(0, 0), (1, None));
+        assert_eq!(next(3, 10), (4, None));
+        assert_eq!(next(4, 10), (5, Some(30)));
+        assert_eq!(next(5, 10), (6, Some(60)));
+        assert_eq!(next(6, 10), (7, Some(120)));
+    }
+
+    #[test]
+    fn the_lockout_is_capped() {
+        assert_eq!(next(11, 10), (12, Some(60 * 60)));
+        // no overflow, however many failures
+        assert_eq!(next(1_000, 10), (1_001, Some(60 * 60)));
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        assert_eq!(next(10, 60 * 60 * 24 + 1), (1, None));
+        assert_eq!(next(10, 60 * 60 * 24 - 1), (11, Some(30 * 64)));
+    }
+}
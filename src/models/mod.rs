@@ -1,2 +1,14 @@
+pub mod audit_entry;
+pub mod export;
+pub mod external_identity;
+pub mod import;
+pub mod login_attempt;
+pub mod oidc_login;
+pub mod one_time_token;
 pub mod person;
+pub mod personal_access_token;
+pub mod recovery_code;
+pub mod refresh_token;
+pub mod revoked_token;
+pub mod session;
 pub mod user;
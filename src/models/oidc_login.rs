@@ -0,0 +1,75 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+
+use crate::{config::db::DbConnection, schema::oidc_logins, toolbox::secure_token};
+
+// how long the user has to log in with the provider
+pub static LOGIN_LIFETIME: i64 = 60 * 10; // seconds
+
+// a login that went to the provider, waiting for it to come back
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "oidc_logins"]
+pub struct OidcLogin {
+    pub id: i32,
+    pub state_hash: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub user_id: Option<i32>, // the account to link, when it isn't a login
+    pub expires_at: DateTime<Utc>,
+    pub browser_hash: String, // of the secret in the cookie of the browser that began it
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "oidc_logins"]
+pub struct InsertableOidcLogin {
+    pub state_hash: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub user_id: Option<i32>,
+    pub expires_at: DateTime<Utc>,
+    pub browser_hash: String,
+}
+
+impl OidcLogin {
+    // the state to send to the provider, the secret to keep in the browser,
+    // and the login they will bring back
+    pub fn start(
+        link_uid: Option<i32>,
+        conn: &DbConnection,
+    ) -> QueryResult<(String, String, OidcLogin)> {
+        // the abandoned ones
+        diesel::delete(oidc_logins::table)
+            .filter(oidc_logins::expires_at.lt(Utc::now()))
+            .execute(conn)?;
+
+        let state = secure_token::generate();
+        let browser_secret = secure_token::generate();
+        let oidc_login = diesel::insert_into(oidc_logins::table)
+            .values(InsertableOidcLogin {
+                state_hash: secure_token::hash(&state),
+                code_verifier: secure_token::generate(),
+                nonce: secure_token::generate(),
+                user_id: link_uid,
+                expires_at: Utc::now() + Duration::seconds(LOGIN_LIFETIME),
+                browser_hash: secure_token::hash(&browser_secret),
+            })
+            .get_result(conn)?;
+        Ok((state, browser_secret, oidc_login))
+    }
+
+    // A state is only good once, and only in the browser that started the login:
+    // a victim's browser sent to the callback with the state of someone else's login
+    // would end up logged in to their account otherwise.
+    pub fn consume(
+        state: &str,
+        browser_secret: &str,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<OidcLogin>> {
+        diesel::delete(oidc_logins::table)
+            .filter(oidc_logins::state_hash.eq(secure_token::hash(state)))
+            .filter(oidc_logins::browser_hash.eq(secure_token::hash(browser_secret)))
+            .filter(oidc_logins::expires_at.gt(Utc::now()))
+            .get_result(conn)
+            .optional()
+    }
+}
@@ -0,0 +1,118 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+
+use crate::{config::db::DbConnection, schema::one_time_tokens, toolbox::secure_token};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Purpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Purpose::PasswordReset => "password_reset",
+            Purpose::EmailVerification => "email_verification",
+        }
+    }
+
+    fn lifetime(self) -> Duration {
+        match self {
+            Purpose::PasswordReset => Duration::hours(1),
+            Purpose::EmailVerification => Duration::days(2),
+        }
+    }
+}
+
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "one_time_tokens"]
+pub struct OneTimeToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub purpose: String,
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "one_time_tokens"]
+pub struct InsertableOneTimeToken {
+    pub user_id: i32,
+    pub purpose: String,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OneTimeToken {
+    // only the last token sent for a purpose is valid
+    pub fn issue(uid: i32, purpose: Purpose, conn: &DbConnection) -> QueryResult<String> {
+        conn.transaction(|| {
+            Self::void_all(uid, purpose, conn)?;
+            Self::add(uid, purpose, conn)
+        })
+    }
+
+    // The tokens already sent stay valid: for what anybody can ask for, so that
+    // asking doesn't void the link the user is about to click.
+    pub fn add(uid: i32, purpose: Purpose, conn: &DbConnection) -> QueryResult<String> {
+        let raw_token = secure_token::generate();
+        diesel::insert_into(one_time_tokens::table)
+            .values(InsertableOneTimeToken {
+                user_id: uid,
+                purpose: purpose.as_str().to_string(),
+                token_hash: secure_token::hash(&raw_token),
+                expires_at: Utc::now() + purpose.lifetime(),
+            })
+            .execute(conn)?;
+        Ok(raw_token)
+    }
+
+    // the tokens already sent can't be used anymore
+    pub fn void_all(
+        uid: i32,
+        purpose: Purpose,
+        conn: &DbConnection,
+    ) -> QueryResult<usize> {
+        diesel::update(one_time_tokens::table)
+            .filter(one_time_tokens::user_id.eq(uid))
+            .filter(one_time_tokens::purpose.eq(purpose.as_str()))
+            .filter(one_time_tokens::used_at.is_null())
+            .set(one_time_tokens::used_at.eq(Utc::now()))
+            .execute(conn)
+    }
+
+    // a token that can still be used, without using it
+    pub fn find_valid(
+        raw_token: &str,
+        purpose: Purpose,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<OneTimeToken>> {
+        one_time_tokens::table
+            .filter(one_time_tokens::token_hash.eq(secure_token::hash(raw_token)))
+            .filter(one_time_tokens::purpose.eq(purpose.as_str()))
+            .filter(one_time_tokens::used_at.is_null())
+            .filter(one_time_tokens::expires_at.gt(Utc::now()))
+            .first(conn)
+            .optional()
+    }
+
+    // returns the user id if the token was valid, it can't be used again
+    pub fn consume(
+        raw_token: &str,
+        purpose: Purpose,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<i32>> {
+        diesel::update(one_time_tokens::table)
+            .filter(one_time_tokens::token_hash.eq(secure_token::hash(raw_token)))
+            .filter(one_time_tokens::purpose.eq(purpose.as_str()))
+            .filter(one_time_tokens::used_at.is_null())
+            .filter(one_time_tokens::expires_at.gt(Utc::now()))
+            .set(one_time_tokens::used_at.eq(Utc::now()))
+            .returning(one_time_tokens::user_id)
+            .get_result(conn)
+            .optional()
+    }
+}
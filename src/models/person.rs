@@ -99,16 +99,4 @@ impl Person {
             .get_result(&conn)?;
         Ok(deleted_person)
     }
-
-    pub fn delete_all_with_uid(
-        uid: i32,
-        pool: &web::Data<Pool>,
-    ) -> Result<usize, CustomError> {
-        let conn = pool.get()?;
-
-        let number_of_deleted_persons = diesel::delete(persons::table)
-            .filter(persons::user_id.eq(uid))
-            .execute(&conn)?;
-        Ok(number_of_deleted_persons)
-    }
 }
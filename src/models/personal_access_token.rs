@@ -0,0 +1,194 @@
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::db::{DbConnection, Pool},
+    models::user::User,
+    schema::{personal_access_tokens, users},
+    toolbox::{errors::CustomError, secure_token},
+};
+
+// so that the middleware, and secret scanners, can tell them from JWTs
+pub static TOKEN_PREFIX: &str = "ages_pat_";
+static MAX_LIFETIME_DAYS: i64 = 366;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "persons:read")]
+    PersonsRead,
+    #[serde(rename = "persons:write")]
+    PersonsWrite,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::PersonsRead => "persons:read",
+            Scope::PersonsWrite => "persons:write",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "persons:read" => Some(Scope::PersonsRead),
+            "persons:write" => Some(Scope::PersonsWrite),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Identifiable, Queryable, Clone, Debug)]
+#[table_name = "personal_access_tokens"]
+pub struct PersonalAccessToken {
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "personal_access_tokens"]
+pub struct InsertablePersonalAccessToken {
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedPersonalAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<i64>, // never expires if absent
+}
+
+// the raw token is only ever shown here
+#[derive(Serialize, Debug)]
+pub struct CreatedPersonalAccessToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub personal_access_token: PersonalAccessToken,
+}
+
+impl PersonalAccessToken {
+    pub fn create(
+        uid: i32,
+        received: ReceivedPersonalAccessToken,
+        pool: &web::Data<Pool>,
+    ) -> Result<CreatedPersonalAccessToken, CustomError> {
+        let name = received.name.trim();
+        if name.is_empty() {
+            return Err(CustomError::new(400, "The token needs a name".to_string()));
+        }
+        if received.scopes.is_empty() {
+            return Err(CustomError::new(
+                400,
+                "The token needs at least one scope".to_string(),
+            ));
+        }
+        let expires_at = match received.expires_in_days {
+            Some(days) if days < 1 || days > MAX_LIFETIME_DAYS => {
+                return Err(CustomError::new(
+                    400,
+                    format!("The token must expire in 1 to {} days", MAX_LIFETIME_DAYS),
+                ))
+            }
+            Some(days) => Some(Utc::now() + Duration::days(days)),
+            None => None,
+        };
+        let mut scopes: Vec<String> = received
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect();
+        scopes.sort();
+        scopes.dedup();
+
+        let conn = pool.get()?;
+        let raw_token = format!("{}{}", TOKEN_PREFIX, secure_token::generate());
+        let personal_access_token = diesel::insert_into(personal_access_tokens::table)
+            .values(InsertablePersonalAccessToken {
+                user_id: uid,
+                name: name.to_string(),
+                token_hash: secure_token::hash(&raw_token),
+                scopes,
+                expires_at,
+            })
+            .get_result(&conn)?;
+        Ok(CreatedPersonalAccessToken {
+            token: raw_token,
+            personal_access_token,
+        })
+    }
+
+    pub fn find_all(
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<Vec<PersonalAccessToken>, CustomError> {
+        let conn = pool.get()?;
+        let personal_access_tokens = personal_access_tokens::table
+            .filter(personal_access_tokens::user_id.eq(uid))
+            .order(personal_access_tokens::created_at.desc())
+            .load(&conn)?;
+        Ok(personal_access_tokens)
+    }
+
+    pub fn revoke(
+        uid: i32,
+        token_id: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<PersonalAccessToken, CustomError> {
+        let conn = pool.get()?;
+        let revoked_token = diesel::delete(personal_access_tokens::table)
+            .filter(personal_access_tokens::id.eq(token_id))
+            .filter(personal_access_tokens::user_id.eq(uid))
+            .get_result(&conn)?;
+        Ok(revoked_token)
+    }
+
+    pub fn revoke_all_for_user(uid: i32, conn: &DbConnection) -> QueryResult<usize> {
+        diesel::delete(personal_access_tokens::table)
+            .filter(personal_access_tokens::user_id.eq(uid))
+            .execute(conn)
+    }
+
+    // for the authentication middleware: the token and its owner, if it is valid
+    pub fn authenticate(
+        raw_token: &str,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<(PersonalAccessToken, User)>> {
+        let found = personal_access_tokens::table
+            .inner_join(users::table)
+            .filter(personal_access_tokens::token_hash.eq(secure_token::hash(raw_token)))
+            .first::<(PersonalAccessToken, User)>(conn)
+            .optional()?;
+        let (token, user) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if matches!(token.expires_at, Some(expires_at) if expires_at < Utc::now()) {
+            return Ok(None);
+        }
+        diesel::update(&token)
+            .set(personal_access_tokens::last_used_at.eq(Utc::now()))
+            .execute(conn)?;
+        Ok(Some((token, user)))
+    }
+
+    // unknown scopes, from a later version, are ignored
+    pub fn granted_scopes(&self) -> Vec<Scope> {
+        self.scopes
+            .iter()
+            .filter_map(|scope| Scope::parse(scope))
+            .collect()
+    }
+}
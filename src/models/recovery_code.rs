@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    config::db::DbConnection,
+    schema::recovery_codes,
+    toolbox::{secure_token, totp},
+};
+
+static RECOVERY_CODES_PER_USER: usize = 10;
+
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "recovery_codes"]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "recovery_codes"]
+pub struct InsertableRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+impl RecoveryCode {
+    // replaces the previous codes, the new ones are shown to the user only once
+    pub fn regenerate(uid: i32, conn: &DbConnection) -> QueryResult<Vec<String>> {
+        Self::delete_all(uid, conn)?;
+        let codes: Vec<String> = (0..RECOVERY_CODES_PER_USER)
+            .map(|_| totp::generate_recovery_code())
+            .collect();
+        let insertable_codes: Vec<InsertableRecoveryCode> = codes
+            .iter()
+            .map(|code| InsertableRecoveryCode {
+                user_id: uid,
+                code_hash: secure_token::hash(&totp::normalize_recovery_code(code)),
+            })
+            .collect();
+        diesel::insert_into(recovery_codes::table)
+            .values(&insertable_codes)
+            .execute(conn)?;
+        Ok(codes)
+    }
+
+    // true if the code was valid, it can't be used again
+    pub fn consume(uid: i32, code: &str, conn: &DbConnection) -> QueryResult<bool> {
+        let code_hash = secure_token::hash(&totp::normalize_recovery_code(code));
+        let consumed = diesel::update(recovery_codes::table)
+            .filter(recovery_codes::user_id.eq(uid))
+            .filter(recovery_codes::code_hash.eq(code_hash))
+            .filter(recovery_codes::used_at.is_null())
+            .set(recovery_codes::used_at.eq(Utc::now()))
+            .execute(conn)?;
+        Ok(consumed > 0)
+    }
+
+    pub fn delete_all(uid: i32, conn: &DbConnection) -> QueryResult<usize> {
+        diesel::delete(recovery_codes::table)
+            .filter(recovery_codes::user_id.eq(uid))
+            .execute(conn)
+    }
+}
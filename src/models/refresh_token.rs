@@ -0,0 +1,214 @@
+use actix_web::web;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    config::db::{DbConnection, Pool},
+    models::{session::Session, user::User},
+    schema::refresh_tokens,
+    toolbox::{errors::CustomError, secure_token},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "refresh_tokens"]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: String,
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "refresh_tokens"]
+pub struct InsertableRefreshToken {
+    pub user_id: i32,
+    pub family_id: String,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedRefreshToken {
+    pub refresh_token: String,
+}
+
+// what we found when looking up a refresh token, decided inside the transaction
+enum Rotation {
+    Rotated(Box<User>, i32, String), // and the id of the session
+    Expired,
+    Reused,
+}
+
+impl RefreshToken {
+    // a login starts a new family of refresh tokens, along with its session
+    pub fn start_family(
+        uid: i32,
+        family_id: &str,
+        lifetime: i64, // seconds
+        conn: &DbConnection,
+    ) -> Result<String, CustomError> {
+        Self::insert(uid, family_id.to_string(), lifetime, conn)
+    }
+
+    // trade a refresh token for a new one of the same family.
+    // Presenting a token that was already traded means it leaked somewhere,
+    // so the whole family is revoked.
+    pub fn rotate(
+        raw_token: &str,
+        lifetime: i64, // seconds, of the new token and of the session
+        pool: &web::Data<Pool>,
+    ) -> Result<(User, i32, String), CustomError> {
+        let conn = pool.get()?;
+        let rotation = conn.transaction::<_, CustomError, _>(|| {
+            let token = match refresh_tokens::table
+                .filter(refresh_tokens::token_hash.eq(secure_token::hash(raw_token)))
+                .for_update()
+                .first::<RefreshToken>(&conn)
+            {
+                Ok(token) => token,
+                Err(diesel::result::Error::NotFound) => {
+                    return Err(CustomError::new(
+                        401,
+                        "Invalid refresh token".to_string(),
+                    ))
+                }
+                Err(error) => return Err(error.into()),
+            };
+
+            if token.revoked_at.is_some() {
+                return Err(CustomError::new(
+                    401,
+                    "This refresh token has been revoked".to_string(),
+                ));
+            }
+            if token.used_at.is_some() {
+                Self::revoke_family(&token.family_id, &conn)?;
+                return Ok(Rotation::Reused);
+            }
+            if token.expires_at < Utc::now() {
+                return Ok(Rotation::Expired);
+            }
+
+            diesel::update(&token)
+                .set(refresh_tokens::used_at.eq(Utc::now()))
+                .execute(&conn)?;
+            let user = User::find_user_by_id(&token.user_id, &conn)?;
+            let session = match Session::extend(&token.family_id, lifetime, &conn)? {
+                Some(session) => session,
+                None => return Ok(Rotation::Expired),
+            };
+            let new_token =
+                Self::insert(token.user_id, token.family_id, lifetime, &conn)?;
+            Ok(Rotation::Rotated(Box::new(user), session.id, new_token))
+        })?;
+
+        match rotation {
+            Rotation::Rotated(user, session_id, new_token) => {
+                Ok((*user, session_id, new_token))
+            }
+            Rotation::Expired => Err(CustomError::new(
+                401,
+                "This refresh token has expired".to_string(),
+            )),
+            Rotation::Reused => {
+                warn!("A refresh token was reused, its family has been revoked");
+                Err(CustomError::new(
+                    401,
+                    "This refresh token was already used".to_string(),
+                ))
+            }
+        }
+    }
+
+    // the session of the family ends with it
+    pub fn revoke_family(family_id: &str, conn: &DbConnection) -> QueryResult<usize> {
+        Session::revoke_family(family_id, conn)?;
+        diesel::update(refresh_tokens::table)
+            .filter(refresh_tokens::family_id.eq(family_id))
+            .filter(refresh_tokens::revoked_at.is_null())
+            .set(refresh_tokens::revoked_at.eq(Utc::now()))
+            .execute(conn)
+    }
+
+    pub fn revoke_all_for_user(uid: i32, conn: &DbConnection) -> QueryResult<usize> {
+        Session::revoke_all_for_user(uid, conn)?;
+        diesel::update(refresh_tokens::table)
+            .filter(refresh_tokens::user_id.eq(uid))
+            .filter(refresh_tokens::revoked_at.is_null())
+            .set(refresh_tokens::revoked_at.eq(Utc::now()))
+            .execute(conn)
+    }
+
+    // revoke the family of a token presented on logout, if it is ours
+    pub fn revoke_family_of(
+        uid: i32,
+        raw_token: &str,
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        let token = refresh_tokens::table
+            .filter(refresh_tokens::token_hash.eq(secure_token::hash(raw_token)))
+            .filter(refresh_tokens::user_id.eq(uid))
+            .first::<RefreshToken>(&conn)
+            .optional()?;
+        if let Some(token) = token {
+            Self::revoke_family(&token.family_id, &conn)?;
+        }
+        Ok(())
+    }
+
+    fn insert(
+        uid: i32,
+        family_id: String,
+        lifetime: i64,
+        conn: &DbConnection,
+    ) -> Result<String, CustomError> {
+        let raw_token = secure_token::generate();
+        let insertable_token = InsertableRefreshToken {
+            user_id: uid,
+            family_id,
+            token_hash: secure_token::hash(&raw_token),
+            expires_at: Utc::now() + Duration::seconds(lifetime),
+        };
+        diesel::insert_into(refresh_tokens::table)
+            .values(insertable_token)
+            .execute(conn)?;
+        Ok(raw_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::db;
+
+    #[test]
+    fn a_reused_token_revokes_its_whole_family() {
+        let pool = db::test_pool();
+        let lifetime = 60 * 60;
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let (_, first) =
+            Session::start(user.id, Default::default(), lifetime, &pool).unwrap();
+
+        let (_, _, second) = RefreshToken::rotate(&first, lifetime, &pool).unwrap();
+        let reused = RefreshToken::rotate(&first, lifetime, &pool).unwrap_err();
+        assert_eq!(reused.error_status_code, 401);
+
+        // the newest token went down with the family
+        let refused = RefreshToken::rotate(&second, lifetime, &pool).unwrap_err();
+        assert_eq!(refused.error_status_code, 401);
+        let conn = pool.get().unwrap();
+        let live = refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user.id))
+            .filter(refresh_tokens::revoked_at.is_null())
+            .count()
+            .get_result::<i64>(&conn)
+            .unwrap();
+        assert_eq!(live, 0);
+    }
+}
@@ -0,0 +1,50 @@
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+
+use crate::{
+    config::db::{DbConnection, Pool},
+    schema::revoked_tokens,
+    toolbox::errors::CustomError,
+};
+
+#[derive(Insertable, Queryable, Clone, Debug)]
+#[table_name = "revoked_tokens"]
+pub struct RevokedToken {
+    pub jti: String,
+    pub user_id: i32,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl RevokedToken {
+    pub fn revoke(
+        jti: &str,
+        uid: i32,
+        expires_at: DateTime<Utc>,
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        let revoked_token = RevokedToken {
+            jti: jti.to_string(),
+            user_id: uid,
+            expires_at,
+        };
+        diesel::insert_into(revoked_tokens::table)
+            .values(revoked_token)
+            .on_conflict_do_nothing()
+            .execute(&conn)?;
+
+        // an expired token is rejected anyway, no need to remember it
+        diesel::delete(revoked_tokens::table)
+            .filter(revoked_tokens::expires_at.lt(Utc::now()))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    pub fn is_revoked(jti: &str, conn: &DbConnection) -> QueryResult<bool> {
+        diesel::select(diesel::dsl::exists(
+            revoked_tokens::table.filter(revoked_tokens::jti.eq(jti)),
+        ))
+        .get_result(conn)
+    }
+}
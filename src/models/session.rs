@@ -0,0 +1,237 @@
+use actix_web::{http::header, web, HttpRequest};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::db::{DbConnection, Pool},
+    models::refresh_token::RefreshToken,
+    schema::sessions,
+    toolbox::errors::CustomError,
+};
+
+// last_seen_at is written at most that often, not on every request
+static LAST_SEEN_PRECISION: i64 = 60; // seconds
+static USER_AGENT_MAX_LENGTH: usize = 512;
+
+// a login on a device, alive as long as the refresh tokens of its family
+#[derive(Identifiable, Queryable, Clone, Debug)]
+#[table_name = "sessions"]
+pub struct Session {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "sessions"]
+pub struct InsertableSession {
+    pub user_id: i32,
+    pub family_id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+// the device a login comes from
+#[derive(Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(request: &HttpRequest) -> Self {
+        Self {
+            user_agent: request
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(|user_agent| {
+                    user_agent.chars().take(USER_AGENT_MAX_LENGTH).collect()
+                }),
+            ip_address: request.peer_addr().map(|address| address.ip().to_string()),
+        }
+    }
+}
+
+// what users see of their sessions
+#[derive(Serialize, Debug)]
+pub struct SessionView {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub current: bool, // the one of this request
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionFilter {
+    // the ended sessions too, for the login history
+    #[serde(default)]
+    pub all: bool,
+}
+
+impl Session {
+    // a login: a session and the first refresh token of its family
+    pub fn start(
+        uid: i32,
+        client: ClientInfo,
+        lifetime: i64, // seconds, the refresh token lifetime
+        pool: &web::Data<Pool>,
+    ) -> Result<(Session, String), CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let family_id = Uuid::new_v4().to_string();
+            let refresh_token =
+                RefreshToken::start_family(uid, &family_id, lifetime, &conn)?;
+            let session = diesel::insert_into(sessions::table)
+                .values(InsertableSession {
+                    user_id: uid,
+                    family_id,
+                    user_agent: client.user_agent,
+                    ip_address: client.ip_address,
+                    expires_at: Utc::now() + Duration::seconds(lifetime),
+                })
+                .get_result(&conn)?;
+            Ok((session, refresh_token))
+        })
+    }
+
+    // when its refresh token is traded
+    pub fn extend(
+        family_id: &str,
+        lifetime: i64, // seconds
+        conn: &DbConnection,
+    ) -> QueryResult<Option<Session>> {
+        diesel::update(sessions::table)
+            .filter(sessions::family_id.eq(family_id))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::last_seen_at.eq(Utc::now()),
+                sessions::expires_at.eq(Utc::now() + Duration::seconds(lifetime)),
+            ))
+            .get_result(conn)
+            .optional()
+    }
+
+    // for the authentication middleware: whether the session of a JWT is still on
+    pub fn check(sid: i32, uid: i32, conn: &DbConnection) -> QueryResult<bool> {
+        let session = sessions::table
+            .find(sid)
+            .filter(sessions::user_id.eq(uid))
+            .first::<Session>(conn)
+            .optional()?;
+        let session = match session {
+            Some(session) if session.is_active() => session,
+            _ => return Ok(false),
+        };
+        if Utc::now() - session.last_seen_at > Duration::seconds(LAST_SEEN_PRECISION) {
+            diesel::update(&session)
+                .set(sessions::last_seen_at.eq(Utc::now()))
+                .execute(conn)?;
+        }
+        Ok(true)
+    }
+
+    // the latest first
+    pub fn find_all(
+        uid: i32,
+        current_sid: Option<i32>,
+        filter: &SessionFilter,
+        pool: &web::Data<Pool>,
+    ) -> Result<Vec<SessionView>, CustomError> {
+        let conn = pool.get()?;
+        let found = sessions::table
+            .filter(sessions::user_id.eq(uid))
+            .order(sessions::last_seen_at.desc())
+            .load::<Session>(&conn)?;
+        Ok(found
+            .into_iter()
+            .filter(|session| filter.all || session.is_active())
+            .map(|session| session.view(current_sid))
+            .collect())
+    }
+
+    // logs the device out: its refresh tokens are revoked, and its JWTs refused
+    pub fn revoke(uid: i32, sid: i32, pool: &web::Data<Pool>) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        let session = sessions::table
+            .find(sid)
+            .filter(sessions::user_id.eq(uid))
+            .first::<Session>(&conn)
+            .optional()?
+            .filter(Session::is_active)
+            .ok_or_else(|| CustomError::new(404, "No such active session".to_string()))?;
+        RefreshToken::revoke_family(&session.family_id, &conn)?;
+        Ok(())
+    }
+
+    // by RefreshToken, when it revokes the family
+    pub fn revoke_family(family_id: &str, conn: &DbConnection) -> QueryResult<usize> {
+        diesel::update(sessions::table)
+            .filter(sessions::family_id.eq(family_id))
+            .filter(sessions::revoked_at.is_null())
+            .set(sessions::revoked_at.eq(Utc::now()))
+            .execute(conn)
+    }
+
+    pub fn revoke_all_for_user(uid: i32, conn: &DbConnection) -> QueryResult<usize> {
+        diesel::update(sessions::table)
+            .filter(sessions::user_id.eq(uid))
+            .filter(sessions::revoked_at.is_null())
+            .set(sessions::revoked_at.eq(Utc::now()))
+            .execute(conn)
+    }
+
+    fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at > Utc::now()
+    }
+
+    pub fn view(self, current_sid: Option<i32>) -> SessionView {
+        SessionView {
+            id: self.id,
+            user_agent: self.user_agent,
+            ip_address: self.ip_address,
+            created_at: self.created_at,
+            last_seen_at: self.last_seen_at,
+            expires_at: self.expires_at,
+            revoked_at: self.revoked_at,
+            current: current_sid == Some(self.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::db, models::user::User};
+
+    #[test]
+    fn only_the_owner_revokes_a_session() {
+        let pool = db::test_pool();
+        let conn = pool.get().unwrap();
+        let (owner, stranger) =
+            (User::insert_for_tests(&conn), User::insert_for_tests(&conn));
+        drop(conn);
+        let (session, _) =
+            Session::start(owner.id, Default::default(), 60, &pool).unwrap();
+
+        let refused = Session::revoke(stranger.id, session.id, &pool).unwrap_err();
+        assert_eq!(refused.error_status_code, 404);
+        assert!(Session::check(session.id, owner.id, &pool.get().unwrap()).unwrap());
+
+        Session::revoke(owner.id, session.id, &pool).unwrap();
+        assert!(!Session::check(session.id, owner.id, &pool.get().unwrap()).unwrap());
+    }
+}
@@ -1,14 +1,43 @@
 use actix_web::web;
-use diesel::prelude::*;
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    prelude::*,
+    result::{DatabaseErrorKind, Error as DieselError},
+    sql_types::{BigInt, Nullable, Text},
+};
 
 use crate::{
     config::db::DbConnection,
     config::db::Pool,
-    schema::users::{self, dsl::*},
-    toolbox::errors::CustomError,
+    models::{
+        audit_entry::{Action, AuditEntry},
+        one_time_token::{OneTimeToken, Purpose},
+        personal_access_token::PersonalAccessToken,
+        recovery_code::RecoveryCode,
+        refresh_token::RefreshToken,
+    },
+    schema::{
+        external_identities, oidc_logins, one_time_tokens, personal_access_tokens,
+        persons, recovery_codes, refresh_tokens, revoked_tokens, sessions,
+        users::{self, dsl::*},
+    },
+    toolbox::{
+        errors::CustomError,
+        hashing::{PasswordHashing, Verification},
+        pagination::Page,
+        password_policy::PasswordPolicy,
+        totp, username,
+    },
 };
-use bcrypt::{hash, verify, DEFAULT_COST};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+#[cfg(test)]
+pub static TEST_PASSWORD: &str = "correct horse battery staple";
+
+// to compare usernames and email addresses whatever the case, like the unique indexes do
+sql_function!(fn lower(x: Nullable<Text>) -> Nullable<Text>);
 
 #[derive(
     Serialize, Deserialize, Identifiable, AsChangeset, Insertable, Queryable, Clone, Debug,
@@ -18,6 +47,31 @@ pub struct User {
     pub id: i32,
     pub username: String,
     pub password: String,
+    pub token_version: i32,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub password_reset_required: bool, // set by an administrator
+    pub deletion_requested_at: Option<DateTime<Utc>>, // purged after the grace period
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Insertable, Queryable, Debug)]
@@ -25,99 +79,1228 @@ pub struct User {
 pub struct ReceivedUser {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub email: Option<String>, // optional, on signup only
+}
+
+// what the user can see of their account
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Account {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+    pub role: String,
+}
+
+// what the administrators see of an account
+#[derive(Serialize, Debug)]
+pub struct UserSummary {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub password_reset_required: bool,
+    pub deletion_requested_at: Option<DateTime<Utc>>,
+    pub person_count: i64,
+}
+
+// an account on its way out, which can still be restored
+#[derive(Serialize, Debug)]
+pub struct PendingDeletion {
+    pub username: String,
+    pub deletion_requested_at: DateTime<Utc>,
+    pub restorable_until: DateTime<Utc>, // purged soon after
+}
+
+// what purging an account removed, the number of rows of each table
+#[derive(Serialize, Debug)]
+pub struct DeletionSummary {
+    pub user_id: i32,
+    pub username: String,
+    pub persons: usize,
+    pub sessions: usize,
+    pub refresh_tokens: usize,
+    pub revoked_tokens: usize,
+    pub personal_access_tokens: usize,
+    pub external_identities: usize,
+    pub oidc_logins: usize, // identity provider links under way
+    pub recovery_codes: usize,
+    pub one_time_tokens: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserSearch {
+    pub search: Option<String>, // part of the username or email address
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedEmailChange {
+    pub email: Option<String>, // null to remove the address
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedPasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedUsernameChange {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedForgottenPassword {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedPasswordReset {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceivedTotpCode {
+    pub code: String, // from the authenticator app, or a recovery code
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
 }
 
 impl User {
     pub fn signup(
         received_user: ReceivedUser,
+        password_policy: &PasswordPolicy,
+        hashing: &PasswordHashing,
         pool: &web::Data<Pool>,
     ) -> Result<User, CustomError> {
+        password_policy.check(&received_user.username, &received_user.password)?;
+        let normalized_username = username::normalize(&received_user.username)?;
         let conn = pool.get()?;
-        if Self::user_already_exists(&received_user.username, &conn) {
-            return Err(CustomError::new(
-                202,
-                format!("User '{}' is already registered", &received_user.username),
-            ));
-        }
-        let hashed_passwd = hash(&received_user.password, DEFAULT_COST)?;
+        let received_email = match &received_user.email {
+            Some(received_email) => Some(Self::check_email(received_email, None, &conn)?),
+            None => None,
+        };
+        let hashed_passwd = hashing.hash(&received_user.password)?;
         let insertable_user = ReceivedUser {
-            username: received_user.username,
+            username: normalized_username,
             password: hashed_passwd,
+            email: received_email,
         };
         let registered_user = diesel::insert_into(users)
             .values(&insertable_user)
-            .get_result(&conn)?;
+            .get_result(&conn)
+            .map_err(|error| Self::conflict_error(error, &insertable_user.username))?;
         Ok(registered_user)
     }
 
     pub fn login(
         received_login: &ReceivedUser,
+        hashing: &PasswordHashing,
         pool: &web::Data<Pool>,
     ) -> Result<User, CustomError> {
         let conn = pool.get()?;
 
         if received_login.password.is_empty() {
-            return Err(CustomError::new(500, "Password is empty".to_string()));
+            return Err(CustomError::new(400, "Password is empty".to_string()));
         }
 
-        let users_with_username = users
-            .filter(username.eq(&received_login.username))
-            .get_results::<User>(&conn)?;
+        let mismatch = || CustomError::new(401, "Password doesn't match".to_string());
+        let matching_user = match Self::find_by_username(&received_login.username, &conn)?
+        {
+            Some(matching_user) => matching_user,
+            None => {
+                hashing.verify_nothing(&received_login.password);
+                return Err(mismatch());
+            }
+        };
+        let verification =
+            hashing.verify(&received_login.password, &matching_user.password)?;
+        if verification == Verification::Mismatch {
+            return Err(mismatch());
+        }
+        matching_user.ensure_can_log_in()?;
 
-        let matching_user =
-            Self::find_matching_user(&received_login.password, users_with_username)?;
+        // hashes made with an older scheme or older parameters are upgraded in place
+        if verification == Verification::MatchNeedsRehash {
+            let rehashed = hashing.hash(&received_login.password).and_then(|new_hash| {
+                diesel::update(users::table)
+                    .filter(users::id.eq(matching_user.id))
+                    .set(password.eq(new_hash))
+                    .execute(&conn)
+                    .map_err(CustomError::from)
+            });
+            if let Err(error) = rehashed {
+                warn!(
+                    "Could not rehash the password of user {}: {}",
+                    matching_user.id, error
+                );
+            }
+        }
 
         Ok(matching_user)
     }
 
-    pub fn update(
+    // all the tokens issued before are revoked, on every device
+    pub fn change_password(
+        uid: i32,
+        change: ReceivedPasswordChange,
+        password_policy: &PasswordPolicy,
+        hashing: &PasswordHashing,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let conn = pool.get()?;
+        let user = Self::find_user_by_id(&uid, &conn)?;
+        if hashing.verify(&change.current_password, &user.password)?
+            == Verification::Mismatch
+        {
+            return Err(CustomError::new(
+                403,
+                "The current password doesn't match".to_string(),
+            ));
+        }
+        password_policy.check(&user.username, &change.new_password)?;
+
+        let hashed_passwd = hashing.hash(&change.new_password)?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = diesel::update(users::table)
+                .filter(users::id.eq(uid))
+                .set((
+                    password.eq(hashed_passwd),
+                    token_version.eq(token_version + 1),
+                ))
+                .get_result(&conn)?;
+            RefreshToken::revoke_all_for_user(uid, &conn)?;
+            Ok(user)
+        })
+    }
+
+    // The verified address of the user and a reset token to send there, none if
+    // there is no such user or no address: a token nobody receives is of no use.
+    pub fn request_password_reset(
+        forgotten: &ReceivedForgottenPassword,
+        pool: &web::Data<Pool>,
+    ) -> Result<Option<(String, String)>, CustomError> {
+        let conn = pool.get()?;
+        let user = match Self::find_by_username(&forgotten.username, &conn)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let address = match user.contact_address() {
+            Some(address) => address.to_string(),
+            None => {
+                info!(
+                    "No address to send a password reset to for user {}",
+                    user.id
+                );
+                return Ok(None);
+            }
+        };
+        let reset_token = OneTimeToken::add(user.id, Purpose::PasswordReset, &conn)?;
+        Ok(Some((address, reset_token)))
+    }
+
+    // like a password change, all the tokens issued before are revoked,
+    // and the personal access tokens too, in case someone else made some
+    pub fn reset_password(
+        reset: ReceivedPasswordReset,
+        password_policy: &PasswordPolicy,
+        hashing: &PasswordHashing,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let invalid_token =
+            || CustomError::new(400, "Invalid or expired reset token".to_string());
+        let conn = pool.get()?;
+
+        // the token is only used once the new password is accepted
+        let token =
+            OneTimeToken::find_valid(&reset.token, Purpose::PasswordReset, &conn)?
+                .ok_or_else(invalid_token)?;
+        let user = Self::find_user_by_id(&token.user_id, &conn)?;
+        password_policy.check(&user.username, &reset.new_password)?;
+        let hashed_passwd = hashing.hash(&reset.new_password)?;
+
+        conn.transaction::<_, CustomError, _>(|| {
+            let uid = OneTimeToken::consume(&reset.token, Purpose::PasswordReset, &conn)?
+                .ok_or_else(invalid_token)?;
+            // the other links asked for meanwhile
+            OneTimeToken::void_all(uid, Purpose::PasswordReset, &conn)?;
+            let user = diesel::update(users::table)
+                .filter(users::id.eq(uid))
+                .set((
+                    password.eq(hashed_passwd),
+                    token_version.eq(token_version + 1),
+                    password_reset_required.eq(false),
+                ))
+                .get_result(&conn)?;
+            RefreshToken::revoke_all_for_user(uid, &conn)?;
+            PersonalAccessToken::revoke_all_for_user(uid, &conn)?;
+            Ok(user)
+        })
+    }
+
+    // where to send emails about the account, only once we know the address is theirs
+    pub fn contact_address(&self) -> Option<&str> {
+        match self.email_verified {
+            true => self.email.as_deref(),
+            false => None,
+        }
+    }
+
+    pub fn account(&self) -> Account {
+        Account {
+            id: self.id,
+            username: self.username.clone(),
+            email: self.email.clone(),
+            email_verified: self.email_verified,
+            totp_enabled: self.totp_enabled,
+            role: self.role.clone(),
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin.as_str()
+    }
+
+    // once the credentials are right, what could still keep the user out
+    pub fn ensure_can_log_in(&self) -> Result<(), CustomError> {
+        self.ensure_active()?;
+        if self.password_reset_required {
+            return Err(CustomError::new(
+                403,
+                "This account needs a new password, reset it with /auth/forgot"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // enough for a login through an identity provider, which doesn't use the password
+    pub fn ensure_active(&self) -> Result<(), CustomError> {
+        if self.disabled_at.is_some() {
+            return Err(CustomError::new(
+                403,
+                "This account is disabled".to_string(),
+            ));
+        }
+        if self.deletion_requested_at.is_some() {
+            return Err(CustomError::new(
+                403,
+                "This account is to be deleted, restore it with /auth/restore"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // a new address has to be verified again
+    pub fn change_email(
         uid: i32,
-        new_data: ReceivedUser,
+        change: ReceivedEmailChange,
         pool: &web::Data<Pool>,
     ) -> Result<User, CustomError> {
         let conn = pool.get()?;
+        let new_email = match &change.email {
+            Some(new_email) => Some(Self::check_email(new_email, Some(uid), &conn)?),
+            None => None,
+        };
+        conn.transaction::<_, CustomError, _>(|| {
+            OneTimeToken::void_all(uid, Purpose::EmailVerification, &conn)?;
+            let user = diesel::update(users::table)
+                .filter(users::id.eq(uid))
+                .set((email.eq(new_email), email_verified.eq(false)))
+                .get_result(&conn)?;
+            Ok(user)
+        })
+    }
 
-        let updatable_user_data = User {
-            id: uid,
-            username: new_data.username,
-            password: hash(&new_data.password, DEFAULT_COST)?,
+    // returns the address to send the verification token to
+    pub fn start_email_verification(
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<(String, String), CustomError> {
+        let conn = pool.get()?;
+        let user = Self::find_user_by_id(&uid, &conn)?;
+        let address = match (user.email, user.email_verified) {
+            (Some(_), true) => {
+                return Err(CustomError::new(
+                    409,
+                    "The email address is already verified".to_string(),
+                ))
+            }
+            (Some(address), false) => address,
+            (None, _) => {
+                return Err(CustomError::new(
+                    409,
+                    "There is no email address to verify".to_string(),
+                ))
+            }
         };
+        let verification_token =
+            OneTimeToken::issue(uid, Purpose::EmailVerification, &conn)?;
+        Ok((address, verification_token))
+    }
+
+    pub fn verify_email(
+        raw_token: &str,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let uid =
+                OneTimeToken::consume(raw_token, Purpose::EmailVerification, &conn)?
+                    .ok_or_else(|| {
+                        CustomError::new(
+                            400,
+                            "Invalid or expired verification link".to_string(),
+                        )
+                    })?;
+            let user = diesel::update(users::table)
+                .filter(users::id.eq(uid))
+                .filter(email.is_not_null())
+                .set(email_verified.eq(true))
+                .get_result(&conn)?;
+            Ok(user)
+        })
+    }
+
+    // a well-formed address, that no other account uses
+    pub fn check_email(
+        received_email: &str,
+        uid: Option<i32>,
+        conn: &DbConnection,
+    ) -> Result<String, CustomError> {
+        let received_email = received_email.trim();
+        if received_email.parse::<lettre::Address>().is_err() {
+            return Err(CustomError::new(
+                400,
+                format!("'{}' is not a valid email address", received_email),
+            ));
+        }
+        let taken = diesel::select(diesel::dsl::exists(
+            users
+                .filter(lower(email).eq(received_email.to_lowercase()))
+                .filter(id.ne(uid.unwrap_or(0))),
+        ))
+        .get_result::<bool>(conn)?;
+        if taken {
+            return Err(CustomError::new(
+                409,
+                "This email address is already used by another account".to_string(),
+            ));
+        }
+        Ok(received_email.to_string())
+    }
 
+    pub fn change_username(
+        uid: i32,
+        change: ReceivedUsernameChange,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let normalized_username = username::normalize(&change.username)?;
+        let conn = pool.get()?;
         let user = diesel::update(users::table)
             .filter(users::id.eq(uid))
-            .set(updatable_user_data)
-            .get_result(&conn)?;
+            .set(username.eq(&normalized_username))
+            .get_result(&conn)
+            .map_err(|error| Self::conflict_error(error, &normalized_username))?;
         Ok(user)
     }
 
-    pub fn delete(uid: i32, pool: &web::Data<Pool>) -> Result<User, CustomError> {
+    // log out everywhere: tokens carrying an older version are rejected
+    pub fn revoke_all_tokens(
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
         let conn = pool.get()?;
-        let deleted_user = diesel::delete(users::table)
-            .filter(users::id.eq(uid))
-            .get_result(&conn)?;
-        Ok(deleted_user)
+        conn.transaction::<_, CustomError, _>(|| {
+            diesel::update(users::table)
+                .filter(users::id.eq(uid))
+                .set(token_version.eq(token_version + 1))
+                .execute(&conn)?;
+            RefreshToken::revoke_all_for_user(uid, &conn)?;
+            Ok(())
+        })
+    }
+
+    // the secret is not used until the user confirms it with a code
+    pub fn enroll_totp(
+        uid: i32,
+        issuer: &str,
+        pool: &web::Data<Pool>,
+    ) -> Result<TotpEnrollment, CustomError> {
+        let conn = pool.get()?;
+        let user = Self::find_user_by_id(&uid, &conn)?;
+        if user.totp_enabled {
+            return Err(CustomError::new(
+                409,
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+
+        let secret = totp::generate_secret();
+        diesel::update(&user)
+            .set((
+                totp_secret.eq(Some(&secret)),
+                totp_last_step.eq(None::<i64>),
+            ))
+            .execute(&conn)?;
+        Ok(TotpEnrollment {
+            otpauth_uri: totp::otpauth_uri(&secret, issuer, &user.username),
+            secret,
+        })
     }
 
-    pub fn find_matching_user(
-        passwd: &str,
-        users_to_check_against: Vec<Self>,
-    ) -> Result<Self, CustomError> {
-        for user in users_to_check_against.iter() {
-            if verify(&passwd, &user.password).unwrap() {
-                return Ok(user.clone());
+    // returns the recovery codes, to be shown only once
+    pub fn confirm_totp(
+        uid: i32,
+        code: &str,
+        pool: &web::Data<Pool>,
+    ) -> Result<RecoveryCodes, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = users.find(uid).for_update().first::<User>(&conn)?;
+            if user.totp_enabled {
+                return Err(CustomError::new(
+                    409,
+                    "Two-factor authentication is already enabled".to_string(),
+                ));
+            }
+            let secret = match &user.totp_secret {
+                Some(secret) => secret,
+                None => {
+                    return Err(CustomError::new(
+                        409,
+                        "Start the two-factor enrollment first".to_string(),
+                    ))
+                }
+            };
+            let step = match totp::verify(secret, code, Utc::now().timestamp()) {
+                Some(step) => step,
+                None => return Err(CustomError::new(403, "Invalid code".to_string())),
+            };
+
+            diesel::update(&user)
+                .set((totp_enabled.eq(true), totp_last_step.eq(Some(step))))
+                .execute(&conn)?;
+            let recovery_codes = RecoveryCode::regenerate(uid, &conn)?;
+            Ok(RecoveryCodes { recovery_codes })
+        })
+    }
+
+    pub fn disable_totp(
+        uid: i32,
+        code: &str,
+        pool: &web::Data<Pool>,
+    ) -> Result<(), CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = users.find(uid).for_update().first::<User>(&conn)?;
+            if !user.totp_enabled {
+                return Err(CustomError::new(
+                    409,
+                    "Two-factor authentication is not enabled".to_string(),
+                ));
+            }
+            if !Self::check_second_factor(&user, code, &conn)? {
+                return Err(CustomError::new(403, "Invalid code".to_string()));
+            }
+
+            diesel::update(&user)
+                .set((
+                    totp_secret.eq(None::<String>),
+                    totp_enabled.eq(false),
+                    totp_last_step.eq(None::<i64>),
+                ))
+                .execute(&conn)?;
+            RecoveryCode::delete_all(uid, &conn)?;
+            Ok(())
+        })
+    }
+
+    // the second step of the login, once the password was right
+    pub fn login_second_factor(
+        uid: i32,
+        version: i32,
+        code: &str,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = users.find(uid).for_update().first::<User>(&conn)?;
+            // the password changed, or 2FA was disabled, since the first step
+            if user.token_version != version || !user.totp_enabled {
+                return Err(CustomError::new(
+                    401,
+                    "This challenge is no longer valid, log in again".to_string(),
+                ));
+            }
+            if !Self::check_second_factor(&user, code, &conn)? {
+                return Err(CustomError::new(401, "Invalid two-factor code".to_string()));
+            }
+            Ok(user)
+        })
+    }
+
+    // a code from the authenticator app, or a recovery code.
+    // The user row must be locked, so that no code is accepted twice.
+    fn check_second_factor(
+        user: &User,
+        code: &str,
+        conn: &DbConnection,
+    ) -> Result<bool, CustomError> {
+        if let Some(secret) = &user.totp_secret {
+            if let Some(step) = totp::verify(secret, code, Utc::now().timestamp()) {
+                if matches!(user.totp_last_step, Some(last_step) if step <= last_step) {
+                    return Ok(false);
+                }
+                diesel::update(user)
+                    .set(totp_last_step.eq(Some(step)))
+                    .execute(conn)?;
+                return Ok(true);
             }
         }
-        return Err(CustomError::new(400, "Password doesn't match".to_string()));
+        Ok(RecoveryCode::consume(user.id, code, conn)?)
+    }
+
+    // Logged out everywhere, and kept out until restored. The personal access tokens
+    // are refused meanwhile, and work again once the account is restored.
+    pub fn request_deletion(
+        uid: i32,
+        grace_period: Duration,
+        pool: &web::Data<Pool>,
+    ) -> Result<PendingDeletion, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let requested_at = Utc::now();
+            let user = diesel::update(users.find(uid))
+                .filter(deletion_requested_at.is_null())
+                .set((
+                    deletion_requested_at.eq(Some(requested_at)),
+                    token_version.eq(token_version + 1),
+                ))
+                .get_result::<User>(&conn)?;
+            RefreshToken::revoke_all_for_user(uid, &conn)?;
+            Ok(PendingDeletion {
+                username: user.username,
+                deletion_requested_at: requested_at,
+                restorable_until: requested_at + grace_period,
+            })
+        })
+    }
+
+    // takes the password, since the account can't log in anymore
+    pub fn restore(
+        received_login: &ReceivedUser,
+        hashing: &PasswordHashing,
+        grace_period: Duration,
+        pool: &web::Data<Pool>,
+    ) -> Result<User, CustomError> {
+        let conn = pool.get()?;
+        let mismatch = || CustomError::new(401, "Password doesn't match".to_string());
+        let matching_user = match Self::find_by_username(&received_login.username, &conn)?
+        {
+            Some(matching_user) => matching_user,
+            None => {
+                hashing.verify_nothing(&received_login.password);
+                return Err(mismatch());
+            }
+        };
+        if hashing.verify(&received_login.password, &matching_user.password)?
+            == Verification::Mismatch
+        {
+            return Err(mismatch());
+        }
+        Self::cancel_deletion(matching_user.id, grace_period, &conn)
+    }
+
+    // for the accounts that log in through an identity provider, and have no password
+    pub fn restore_by_admin(
+        actor_id: i32,
+        uid: i32,
+        grace_period: Duration,
+        pool: &web::Data<Pool>,
+    ) -> Result<UserSummary, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = Self::cancel_deletion(uid, grace_period, &conn)?;
+            AuditEntry::record(
+                Some(actor_id),
+                Action::RestoreUser,
+                Some(uid),
+                json!({}),
+                &conn,
+            )?;
+            Ok(Self::summaries(vec![user], &conn)?.remove(0))
+        })
+    }
+
+    fn cancel_deletion(
+        uid: i32,
+        grace_period: Duration,
+        conn: &DbConnection,
+    ) -> Result<User, CustomError> {
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = users.find(uid).for_update().first::<User>(conn)?;
+            match user.deletion_requested_at {
+                None => Err(CustomError::new(
+                    409,
+                    "This account is not to be deleted".to_string(),
+                )),
+                Some(requested_at) if requested_at + grace_period < Utc::now() => {
+                    Err(CustomError::new(
+                        410,
+                        "This account can't be restored anymore".to_string(),
+                    ))
+                }
+                Some(_) => Ok(diesel::update(&user)
+                    .set(deletion_requested_at.eq(None::<DateTime<Utc>>))
+                    .get_result::<User>(conn)?),
+            }
+        })
+    }
+
+    // For the sweep: the accounts whose grace period is over go for good. One that
+    // fails is left for the next sweep, the others still go.
+    pub fn purge_deleted(
+        grace_period: Duration,
+        pool: &web::Data<Pool>,
+    ) -> Result<Vec<DeletionSummary>, CustomError> {
+        let conn = pool.get()?;
+        let requested_before = Utc::now() - grace_period;
+        let uids = users
+            .filter(deletion_requested_at.lt(requested_before))
+            .select(id)
+            .load::<i32>(&conn)?;
+        let mut summaries = Vec::new();
+        for uid in uids {
+            match Self::purge(uid, requested_before, &conn) {
+                Ok(Some(summary)) => summaries.push(summary),
+                Ok(None) => {}
+                Err(error) => warn!("Could not purge the account {}: {}", uid, error),
+            }
+        }
+        Ok(summaries)
+    }
+
+    // All of the account goes in one transaction, or none of it, unless it was
+    // restored in the meantime. The foreign keys cascade anyway, the rows are deleted
+    // one table at a time to count them.
+    fn purge(
+        uid: i32,
+        requested_before: DateTime<Utc>,
+        conn: &DbConnection,
+    ) -> Result<Option<DeletionSummary>, CustomError> {
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = match users
+                .find(uid)
+                .filter(deletion_requested_at.lt(requested_before))
+                .for_update()
+                .first::<User>(conn)
+                .optional()?
+            {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+            let summary = DeletionSummary {
+                persons: diesel::delete(persons::table.filter(persons::user_id.eq(uid)))
+                    .execute(conn)?,
+                sessions: diesel::delete(
+                    sessions::table.filter(sessions::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                refresh_tokens: diesel::delete(
+                    refresh_tokens::table.filter(refresh_tokens::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                revoked_tokens: diesel::delete(
+                    revoked_tokens::table.filter(revoked_tokens::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                personal_access_tokens: diesel::delete(
+                    personal_access_tokens::table
+                        .filter(personal_access_tokens::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                external_identities: diesel::delete(
+                    external_identities::table
+                        .filter(external_identities::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                oidc_logins: diesel::delete(
+                    oidc_logins::table.filter(oidc_logins::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                recovery_codes: diesel::delete(
+                    recovery_codes::table.filter(recovery_codes::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                one_time_tokens: diesel::delete(
+                    one_time_tokens::table.filter(one_time_tokens::user_id.eq(uid)),
+                )
+                .execute(conn)?,
+                user_id: user.id,
+                username: user.username,
+            };
+            diesel::delete(users.find(uid)).execute(conn)?;
+            Ok(Some(summary))
+        })
+    }
+
+    pub fn find(uid: i32, pool: &web::Data<Pool>) -> Result<User, CustomError> {
+        let conn = pool.get()?;
+        Ok(Self::find_user_by_id(&uid, &conn)?)
     }
 
     pub fn find_user_by_id(uid: &i32, conn: &DbConnection) -> QueryResult<User> {
         users.filter(id.eq(uid)).get_result::<User>(conn)
     }
 
-    pub fn user_already_exists(un: &str, conn: &DbConnection) -> bool {
-        users
-            .filter(username.eq(un))
-            .get_result::<User>(conn)
-            .is_ok()
+    // an account of a name no other test uses, with the password TEST_PASSWORD
+    #[cfg(test)]
+    pub fn insert_for_tests(conn: &DbConnection) -> User {
+        diesel::insert_into(users)
+            .values(ReceivedUser {
+                username: format!("test-{}", uuid::Uuid::new_v4()),
+                password: PasswordHashing::for_tests().hash(TEST_PASSWORD).unwrap(),
+                email: None,
+            })
+            .get_result(conn)
+            .unwrap()
+    }
+
+    // gives the admin role to an existing account, none if there is no such account
+    pub fn grant_admin(
+        name: &str,
+        granted_by: &str, // how it was granted, for the audit trail
+        pool: &web::Data<Pool>,
+    ) -> Result<Option<User>, CustomError> {
+        let conn = pool.get()?;
+        let user = match Self::find_by_username(name, &conn)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        if user.is_admin() {
+            return Ok(Some(user));
+        }
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = diesel::update(&user)
+                .set(role.eq(Role::Admin.as_str()))
+                .get_result::<User>(&conn)?;
+            AuditEntry::record(
+                None,
+                Action::GrantAdmin,
+                Some(user.id),
+                json!({ "granted_by": granted_by }),
+                &conn,
+            )?;
+            Ok(Some(user))
+        })
+    }
+
+    pub fn search(
+        actor_id: i32,
+        search: &UserSearch,
+        page: &Page,
+        pool: &web::Data<Pool>,
+    ) -> Result<Vec<UserSummary>, CustomError> {
+        let conn = pool.get()?;
+        let mut query = users
+            .order(id)
+            .limit(page.limit())
+            .offset(page.offset())
+            .into_boxed();
+        let search_text = search.search.as_deref().map(str::trim).unwrap_or("");
+        if !search_text.is_empty() {
+            let pattern = format!("%{}%", escape_like(&username::fold(search_text)));
+            query = query.filter(
+                lower(username.nullable())
+                    .like(pattern.clone())
+                    .or(lower(email).like(pattern)),
+            );
+        }
+        let found_users = query.load::<User>(&conn)?;
+        AuditEntry::record(
+            Some(actor_id),
+            Action::ListUsers,
+            None,
+            json!({
+                "search": search_text,
+                "limit": page.limit(),
+                "offset": page.offset(),
+            }),
+            &conn,
+        )?;
+        Ok(Self::summaries(found_users, &conn)?)
+    }
+
+    pub fn summary(
+        actor_id: i32,
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<UserSummary, CustomError> {
+        let conn = pool.get()?;
+        let user = Self::find_user_by_id(&uid, &conn)?;
+        AuditEntry::record(
+            Some(actor_id),
+            Action::ViewUser,
+            Some(uid),
+            json!({}),
+            &conn,
+        )?;
+        Ok(Self::summaries(vec![user], &conn)?.remove(0))
+    }
+
+    // logged out everywhere, and kept out until enabled again
+    pub fn disable(
+        actor_id: i32,
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<UserSummary, CustomError> {
+        if actor_id == uid {
+            return Err(CustomError::new(
+                409,
+                "Administrators can't disable their own account".to_string(),
+            ));
+        }
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = users.find(uid).for_update().first::<User>(&conn)?;
+            if user.disabled_at.is_some() {
+                return Err(CustomError::new(
+                    409,
+                    "This account is already disabled".to_string(),
+                ));
+            }
+            let user = diesel::update(&user)
+                .set((
+                    disabled_at.eq(Some(Utc::now())),
+                    token_version.eq(token_version + 1),
+                ))
+                .get_result::<User>(&conn)?;
+            RefreshToken::revoke_all_for_user(uid, &conn)?;
+            AuditEntry::record(
+                Some(actor_id),
+                Action::DisableUser,
+                Some(uid),
+                json!({}),
+                &conn,
+            )?;
+            Ok(Self::summaries(vec![user], &conn)?.remove(0))
+        })
+    }
+
+    pub fn enable(
+        actor_id: i32,
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<UserSummary, CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = users.find(uid).for_update().first::<User>(&conn)?;
+            if user.disabled_at.is_none() {
+                return Err(CustomError::new(
+                    409,
+                    "This account is not disabled".to_string(),
+                ));
+            }
+            let user = diesel::update(&user)
+                .set(disabled_at.eq(None::<DateTime<Utc>>))
+                .get_result::<User>(&conn)?;
+            AuditEntry::record(
+                Some(actor_id),
+                Action::EnableUser,
+                Some(uid),
+                json!({}),
+                &conn,
+            )?;
+            Ok(Self::summaries(vec![user], &conn)?.remove(0))
+        })
+    }
+
+    // Logged out everywhere, with the password refused until it is reset.
+    // Returns a reset token to send to the user.
+    pub fn force_password_reset(
+        actor_id: i32,
+        uid: i32,
+        pool: &web::Data<Pool>,
+    ) -> Result<(User, String), CustomError> {
+        let conn = pool.get()?;
+        conn.transaction::<_, CustomError, _>(|| {
+            let user = diesel::update(users.find(uid))
+                .set((
+                    password_reset_required.eq(true),
+                    token_version.eq(token_version + 1),
+                ))
+                .get_result::<User>(&conn)?;
+            RefreshToken::revoke_all_for_user(uid, &conn)?;
+            PersonalAccessToken::revoke_all_for_user(uid, &conn)?;
+            let reset_token = OneTimeToken::issue(uid, Purpose::PasswordReset, &conn)?;
+            AuditEntry::record(
+                Some(actor_id),
+                Action::ForcePasswordReset,
+                Some(uid),
+                json!({ "emailed": user.contact_address().is_some() }),
+                &conn,
+            )?;
+            Ok((user, reset_token))
+        })
+    }
+
+    // with the number of persons of each, counted in a single query
+    fn summaries(
+        found_users: Vec<User>,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<UserSummary>> {
+        let uids: Vec<i32> = found_users.iter().map(|user| user.id).collect();
+        let person_counts: HashMap<i32, i64> = persons::table
+            .filter(persons::user_id.eq_any(uids))
+            .group_by(persons::user_id)
+            // diesel 1.4 can't mix an aggregate and a column in a select
+            .select((persons::user_id, diesel::dsl::sql::<BigInt>("COUNT(*)")))
+            .load::<(i32, i64)>(conn)?
+            .into_iter()
+            .collect();
+        Ok(found_users
+            .into_iter()
+            .map(|user| UserSummary {
+                person_count: person_counts.get(&user.id).copied().unwrap_or(0),
+                id: user.id,
+                username: user.username,
+                email: user.email,
+                email_verified: user.email_verified,
+                totp_enabled: user.totp_enabled,
+                role: user.role,
+                disabled_at: user.disabled_at,
+                password_reset_required: user.password_reset_required,
+                deletion_requested_at: user.deletion_requested_at,
+            })
+            .collect())
+    }
+
+    // whatever the case, like the unique index
+    pub fn find_by_username(
+        name: &str,
+        conn: &DbConnection,
+    ) -> Result<Option<User>, CustomError> {
+        let normalized_username = username::normalize(name)?;
+        Ok(users
+            .filter(lower(username.nullable()).eq(lower(normalized_username)))
+            .first::<User>(conn)
+            .optional()?)
+    }
+
+    // the unique indexes are what prevents duplicates, even between concurrent requests
+    fn conflict_error(error: DieselError, name: &str) -> CustomError {
+        match &error {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                match info.constraint_name() {
+                    Some("users_username_lower") => CustomError::new(
+                        409,
+                        format!("The username '{}' is already taken", name),
+                    ),
+                    Some("users_email_lower") => CustomError::new(
+                        409,
+                        "This email address is already used by another account"
+                            .to_string(),
+                    ),
+                    _ => error.into(),
+                }
+            }
+            _ => error.into(),
+        }
+    }
+}
+
+// so that "%" and "_" in a search are taken literally
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{db, Config};
+
+    #[test]
+    fn unknown_usernames_and_wrong_passwords_look_the_same() {
+        let pool = db::test_pool();
+        let hashing = PasswordHashing::for_tests();
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let login = |name: &str, secret: &str| {
+            let received_login = ReceivedUser {
+                username: name.to_string(),
+                password: secret.to_string(),
+                email: None,
+            };
+            User::login(&received_login, &hashing, &pool)
+        };
+
+        assert_eq!(login(&user.username, TEST_PASSWORD).unwrap().id, user.id);
+        let wrong_password = login(&user.username, "wrong password").unwrap_err();
+        let unknown_username = login("nobody-at-all", TEST_PASSWORD).unwrap_err();
+        assert_eq!(wrong_password.error_status_code, 401);
+        assert_eq!(unknown_username.error_status_code, 401);
+        assert_eq!(wrong_password.error_message, unknown_username.error_message);
+    }
+
+    // as if the deletion had been asked for that long ago
+    fn request_deletion_ago(uid: i32, ago: Duration, pool: &web::Data<Pool>) {
+        diesel::update(users.find(uid))
+            .set(deletion_requested_at.eq(Some(Utc::now() - ago)))
+            .execute(&pool.get().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn accounts_are_restored_until_the_end_of_the_grace_period() {
+        let pool = db::test_pool();
+        let hashing = PasswordHashing::for_tests();
+        let grace_period = Duration::days(30);
+        let conn = pool.get().unwrap();
+        let (in_time, too_late) =
+            (User::insert_for_tests(&conn), User::insert_for_tests(&conn));
+        drop(conn);
+        let restore = |user: &User| {
+            let received_login = ReceivedUser {
+                username: user.username.clone(),
+                password: TEST_PASSWORD.to_string(),
+                email: None,
+            };
+            User::restore(&received_login, &hashing, grace_period, &pool)
+        };
+
+        request_deletion_ago(in_time.id, grace_period - Duration::minutes(1), &pool);
+        request_deletion_ago(too_late.id, grace_period + Duration::minutes(1), &pool);
+
+        let restored = restore(&in_time).unwrap();
+        assert_eq!(restored.deletion_requested_at, None);
+        // restored already, there is nothing left to cancel
+        assert_eq!(restore(&in_time).unwrap_err().error_status_code, 409);
+        assert_eq!(restore(&too_late).unwrap_err().error_status_code, 410);
+    }
+
+    #[test]
+    fn reset_links_go_to_verified_addresses_and_stay_valid_until_one_is_used() {
+        let pool = db::test_pool();
+        let config = Config::for_tests();
+        let user = User::insert_for_tests(&pool.get().unwrap());
+        let forgotten = ReceivedForgottenPassword {
+            username: user.username.clone(),
+        };
+        let reset = |token: &str| {
+            let received_reset = ReceivedPasswordReset {
+                token: token.to_string(),
+                new_password: "another horse battery staple".to_string(),
+            };
+            User::reset_password(
+                received_reset,
+                &config.password_policy,
+                &config.password_hashing,
+                &pool,
+            )
+        };
+
+        // no address, no token
+        assert!(User::request_password_reset(&forgotten, &pool)
+            .unwrap()
+            .is_none());
+        let tokens = one_time_tokens::table
+            .filter(one_time_tokens::user_id.eq(user.id))
+            .count()
+            .get_result::<i64>(&pool.get().unwrap())
+            .unwrap();
+        assert_eq!(tokens, 0);
+
+        diesel::update(users.find(user.id))
+            .set((email.eq("test@example.com"), email_verified.eq(true)))
+            .execute(&pool.get().unwrap())
+            .unwrap();
+        let (address, first) = User::request_password_reset(&forgotten, &pool)
+            .unwrap()
+            .unwrap();
+        let (_, second) = User::request_password_reset(&forgotten, &pool)
+            .unwrap()
+            .unwrap();
+        assert_eq!(address, "test@example.com");
+
+        assert_eq!(reset(&first).unwrap().id, user.id);
+        assert_eq!(reset(&second).unwrap_err().error_status_code, 400);
+    }
+
+    #[test]
+    fn purging_counts_what_it_deletes() {
+        use crate::models::{
+            person::{Person, ReceivedPerson},
+            personal_access_token::{ReceivedPersonalAccessToken, Scope},
+            revoked_token::RevokedToken,
+            session::Session,
+        };
+
+        let pool = db::test_pool();
+        let grace_period = Duration::days(30);
+        let conn = pool.get().unwrap();
+        let (purged, pending, kept) = (
+            User::insert_for_tests(&conn),
+            User::insert_for_tests(&conn),
+            User::insert_for_tests(&conn),
+        );
+        drop(conn);
+        for user in &[&purged, &pending, &kept] {
+            Session::start(user.id, Default::default(), 60, &pool).unwrap();
+        }
+        Session::start(purged.id, Default::default(), 60, &pool).unwrap();
+        let person = ReceivedPerson {
+            name: "Oncle Jim".to_string(),
+            birthdate: 0,
+        };
+        Person::create(purged.id, person, &pool).unwrap();
+        let expires_at = Utc::now() + Duration::minutes(15);
+        RevokedToken::revoke("a jti", purged.id, expires_at, &pool).unwrap();
+        let token = ReceivedPersonalAccessToken {
+            name: "backup script".to_string(),
+            scopes: vec![Scope::PersonsRead],
+            expires_in_days: None,
+        };
+        PersonalAccessToken::create(purged.id, token, &pool).unwrap();
+        request_deletion_ago(purged.id, grace_period + Duration::minutes(1), &pool);
+        request_deletion_ago(pending.id, grace_period - Duration::minutes(1), &pool);
+
+        let summaries = User::purge_deleted(grace_period, &pool).unwrap();
+        // the database of the tests may have older accounts of its own to purge
+        let summary = summaries
+            .into_iter()
+            .find(|summary| summary.user_id == purged.id)
+            .unwrap();
+        assert_eq!(summary.username, purged.username);
+        assert_eq!(summary.persons, 1);
+        assert_eq!(summary.sessions, 2);
+        assert_eq!(summary.refresh_tokens, 2);
+        assert_eq!(summary.revoked_tokens, 1);
+        assert_eq!(summary.personal_access_tokens, 1);
+        assert_eq!(summary.external_identities, 0);
+        assert_eq!(summary.oidc_logins, 0);
+        assert_eq!(summary.recovery_codes, 0);
+        assert_eq!(summary.one_time_tokens, 0);
+
+        assert!(User::find(purged.id, &pool).is_err());
+        assert!(User::find(pending.id, &pool).is_ok());
+        assert!(User::find(kept.id, &pool).is_ok());
     }
 }
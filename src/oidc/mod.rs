@@ -0,0 +1,298 @@
+use crate::{config::env_or, toolbox::errors::CustomError};
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::Display,
+    sync::{Arc, RwLock},
+};
+use url::Url;
+
+// the discovery document and the keys are fetched again after that
+static METADATA_LIFETIME: i64 = 60 * 60; // seconds
+static RESPONSE_LIMIT: usize = 1 << 20; // bytes
+
+// never the shared-secret ones: the provider doesn't share a secret for id tokens with us
+static ALLOWED_ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+// what we use of the provider's discovery document
+#[derive(Deserialize, Clone, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone)]
+struct Discovered {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    fetched_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+// who the provider says logged in
+#[derive(Deserialize, Clone, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+}
+
+// Login with an OpenID Connect provider, with the authorization code flow and PKCE.
+// The provider's metadata is discovered on first use, so that the API starts without it.
+#[derive(Clone)]
+pub struct OidcProvider {
+    discovery_url: String,
+    client_id: String,
+    client_secret: Option<String>, // none for a public client, PKCE is enough then
+    redirect_url: String,
+    scopes: String,
+    pub create_accounts: bool, // on the first login of someone without an account
+    discovered: Arc<RwLock<Option<Discovered>>>,
+}
+
+impl OidcProvider {
+    // OIDC_DISCOVERY_URL: the provider's /.well-known/openid-configuration,
+    //                     login with a provider is off without it
+    // OIDC_CLIENT_ID and OIDC_CLIENT_SECRET: as registered with the provider
+    // OIDC_REDIRECT_URL: PUBLIC_URL + /auth/oidc/callback by default
+    // OIDC_SCOPES: "openid profile email" by default
+    // OIDC_CREATE_ACCOUNTS: false by default
+    pub fn from_env(public_url: &str) -> anyhow::Result<Option<Self>> {
+        let discovery_url = match env::var("OIDC_DISCOVERY_URL") {
+            Ok(discovery_url) => discovery_url,
+            Err(_) => return Ok(None),
+        };
+        Url::parse(&discovery_url).context("OIDC_DISCOVERY_URL is not a valid URL")?;
+        let client_id = env::var("OIDC_CLIENT_ID")
+            .context("OIDC_DISCOVERY_URL is set but OIDC_CLIENT_ID is not")?;
+        Ok(Some(Self {
+            discovery_url,
+            client_id,
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: env_or(
+                "OIDC_REDIRECT_URL",
+                format!("{}/auth/oidc/callback", public_url),
+            )?,
+            scopes: env_or("OIDC_SCOPES", "openid profile email".to_string())?,
+            create_accounts: env_or("OIDC_CREATE_ACCOUNTS", false)?,
+            discovered: Arc::new(RwLock::new(None)),
+        }))
+    }
+
+    // a provider of the tests, registered as client "ages_api"
+    #[cfg(test)]
+    pub fn for_tests(discovery_url: String) -> Self {
+        Self {
+            discovery_url,
+            client_id: "ages_api".to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:8080/auth/oidc/callback".to_string(),
+            scopes: "openid profile email".to_string(),
+            create_accounts: true,
+            discovered: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    // where to send the user's browser
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, CustomError> {
+        let discovered = self.discover(false).await?;
+        let mut url = Url::parse(&discovered.metadata.authorization_endpoint)
+            .map_err(provider_error)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(String::from(url))
+    }
+
+    // trades the code the provider sent back for a verified id token
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, CustomError> {
+        let discovered = self.discover(false).await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("code_verifier", code_verifier),
+            ("client_id", &self.client_id),
+        ];
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let mut response = awc::Client::default()
+            .post(&discovered.metadata.token_endpoint)
+            .send_form(&form)
+            .await
+            .map_err(provider_error)?;
+        // what the provider says is for our logs, not for the client
+        if !response.status().is_success() {
+            let body = response
+                .body()
+                .limit(RESPONSE_LIMIT)
+                .await
+                .unwrap_or_default();
+            warn!(
+                "The identity provider refused the code with {}: {}",
+                response.status(),
+                String::from_utf8_lossy(&body)
+            );
+            return Err(CustomError::new(
+                502,
+                "The identity provider refused the code".to_string(),
+            ));
+        }
+        let token_response = response
+            .json::<TokenEndpointResponse>()
+            .limit(RESPONSE_LIMIT)
+            .await
+            .map_err(provider_error)?;
+        self.verify_id_token(&token_response.id_token, nonce).await
+    }
+
+    async fn verify_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, CustomError> {
+        let invalid = |reason: &str| {
+            CustomError::new(
+                401,
+                format!("Invalid id token from the provider: {}", reason),
+            )
+        };
+        let header = jsonwebtoken::decode_header(id_token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(invalid("unexpected signing algorithm"));
+        }
+        let find_key = |jwks: &JwkSet| match &header.kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            // a provider with a single key may not name it
+            None if jwks.keys.len() == 1 => Some(jwks.keys[0].clone()),
+            None => None,
+        };
+
+        let mut discovered = self.discover(false).await?;
+        if find_key(&discovered.jwks).is_none() {
+            // the provider may have rotated its keys since we fetched them
+            discovered = self.discover(true).await?;
+        }
+        let jwk = find_key(&discovered.jwks).ok_or_else(|| invalid("unknown key"))?;
+        let decoding_key = DecodingKey::from_jwk(&jwk)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovered.metadata.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        let claims =
+            jsonwebtoken::decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+                .map_err(|error| invalid(&error.to_string()))?
+                .claims;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid("the nonce doesn't match"));
+        }
+        Ok(claims)
+    }
+
+    // the cached metadata and keys, fetched again when stale or when asked to
+    async fn discover(&self, refresh: bool) -> Result<Discovered, CustomError> {
+        if !refresh {
+            if let Some(discovered) = self.discovered.read().unwrap().as_ref() {
+                if Utc::now() - discovered.fetched_at
+                    < Duration::seconds(METADATA_LIFETIME)
+                {
+                    return Ok(discovered.clone());
+                }
+            }
+        }
+        let metadata = fetch_json::<ProviderMetadata>(&self.discovery_url).await?;
+        let jwks = fetch_json::<JwkSet>(&metadata.jwks_uri).await?;
+        let discovered = Discovered {
+            metadata,
+            jwks,
+            fetched_at: Utc::now(),
+        };
+        *self.discovered.write().unwrap() = Some(discovered.clone());
+        Ok(discovered)
+    }
+}
+
+async fn fetch_json<T: DeserializeOwned>(url: &str) -> Result<T, CustomError> {
+    let mut response = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(provider_error)?;
+    if !response.status().is_success() {
+        return Err(provider_error(format!(
+            "{} answered {}",
+            url,
+            response.status()
+        )));
+    }
+    response
+        .json::<T>()
+        .limit(RESPONSE_LIMIT)
+        .await
+        .map_err(provider_error)
+}
+
+fn provider_error(error: impl Display) -> CustomError {
+    CustomError::new(502, format!("The identity provider failed: {}", error))
+}
+
+// PKCE (RFC 7636), with the S256 method
+pub fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rfc_7636_code_challenge() {
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
@@ -1,3 +1,70 @@
+table! {
+    audit_log (id) {
+        id -> Int4,
+        actor_id -> Nullable<Int4>,
+        action -> Varchar,
+        target_user_id -> Nullable<Int4>,
+        details -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    external_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        issuer -> Varchar,
+        subject -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    login_attempts (subject) {
+        subject -> Varchar,
+        failures -> Int4,
+        last_failure_at -> Timestamptz,
+        locked_until -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    oidc_logins (id) {
+        id -> Int4,
+        state_hash -> Varchar,
+        code_verifier -> Varchar,
+        nonce -> Varchar,
+        user_id -> Nullable<Int4>,
+        expires_at -> Timestamptz,
+        browser_hash -> Varchar,
+    }
+}
+
+table! {
+    one_time_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        purpose -> Varchar,
+        token_hash -> Varchar,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    personal_access_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     persons (id) {
         id -> Int4,
@@ -7,17 +74,89 @@ table! {
     }
 }
 
+table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        family_id -> Varchar,
+        token_hash -> Varchar,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    revoked_tokens (jti) {
+        jti -> Varchar,
+        user_id -> Int4,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        family_id -> Varchar,
+        user_agent -> Nullable<Varchar>,
+        ip_address -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     users (id) {
         id -> Int4,
         username -> Varchar,
         password -> Varchar,
+        token_version -> Int4,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        totp_last_step -> Nullable<Int8>,
+        email -> Nullable<Varchar>,
+        email_verified -> Bool,
+        role -> Varchar,
+        disabled_at -> Nullable<Timestamptz>,
+        password_reset_required -> Bool,
+        deletion_requested_at -> Nullable<Timestamptz>,
     }
 }
 
+joinable!(external_identities -> users (user_id));
+joinable!(oidc_logins -> users (user_id));
+joinable!(one_time_tokens -> users (user_id));
+joinable!(personal_access_tokens -> users (user_id));
 joinable!(persons -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(refresh_tokens -> users (user_id));
+joinable!(revoked_tokens -> users (user_id));
+joinable!(sessions -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    audit_log,
+    external_identities,
+    login_attempts,
+    oidc_logins,
+    one_time_tokens,
+    personal_access_tokens,
     persons,
+    recovery_codes,
+    refresh_tokens,
+    revoked_tokens,
+    sessions,
     users,
 );
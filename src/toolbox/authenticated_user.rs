@@ -0,0 +1,78 @@
+use crate::{models::personal_access_token::Scope, toolbox::errors::CustomError};
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use futures::future::{ready, Ready};
+
+// how the caller proved who they are
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    // a JWT from a login
+    Session {
+        jti: String,
+        expires_at: DateTime<Utc>,
+        session_id: i32,
+    },
+    PersonalAccessToken {
+        id: i32,
+        scopes: Vec<Scope>,
+    },
+}
+
+// The identity verified by the authentication middleware, stored in the request
+// extensions. Handlers take it as a parameter: without it, the request never
+// reaches them.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub uid: i32,
+    pub username: String,
+    pub credentials: Credentials,
+}
+
+impl AuthenticatedUser {
+    // a login can do anything, a personal access token only what its scopes allow
+    pub fn require_scope(&self, scope: Scope) -> Result<(), CustomError> {
+        match &self.credentials {
+            Credentials::PersonalAccessToken { scopes, .. }
+                if !scopes.contains(&scope) =>
+            {
+                Err(CustomError::new(
+                    403,
+                    format!("This token lacks the '{}' scope", scope.as_str()),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // the id and expiry of the JWT, to revoke it, and the id of its session
+    pub fn session(&self) -> Result<(&str, DateTime<Utc>, i32), CustomError> {
+        match &self.credentials {
+            Credentials::Session {
+                jti,
+                expires_at,
+                session_id,
+            } => Ok((jti, *expires_at, *session_id)),
+            Credentials::PersonalAccessToken { .. } => Err(CustomError::new(
+                403,
+                "This needs a login, not a personal access token".to_string(),
+            )),
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = CustomError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(match request.extensions().get::<AuthenticatedUser>() {
+            Some(user) => Ok(user.clone()),
+            // the route is not behind the authentication middleware
+            None => Err(CustomError::new(
+                401,
+                "The request is not authenticated".to_string(),
+            )),
+        })
+    }
+}
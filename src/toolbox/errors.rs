@@ -10,6 +10,8 @@ use r2d2; // for the error conversion
 pub struct CustomError {
     pub error_status_code: u16,
     pub error_message: String,
+    pub retry_after: Option<i64>, // seconds, for 429 and 503
+    pub details: Option<serde_json::Value>, // sent as JSON along the message
 }
 
 impl CustomError {
@@ -17,8 +19,20 @@ impl CustomError {
         Self {
             error_status_code,
             error_message,
+            retry_after: None,
+            details: None,
         }
     }
+
+    pub fn with_retry_after(mut self, seconds: i64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
 }
 
 impl fmt::Display for CustomError {
@@ -64,6 +78,11 @@ impl From<bcrypt::BcryptError> for CustomError {
         CustomError::new(500, error.to_string())
     }
 }
+impl From<argon2::password_hash::Error> for CustomError {
+    fn from(error: argon2::password_hash::Error) -> CustomError {
+        CustomError::new(500, error.to_string())
+    }
+}
 impl From<r2d2::Error> for CustomError {
     fn from(error: r2d2::Error) -> CustomError {
         CustomError::new(500, error.to_string())
@@ -84,6 +103,16 @@ impl ResponseError for CustomError {
         // };
         let error_message = &self.error_message;
 
-        HttpResponse::build(status_code).body(error_message)
+        let mut response = HttpResponse::build(status_code);
+        if let Some(seconds) = self.retry_after {
+            response.header(http::header::RETRY_AFTER, seconds.to_string());
+        }
+        match &self.details {
+            Some(details) => response.json(serde_json::json!({
+                "error": error_message,
+                "details": details,
+            })),
+            None => response.body(error_message),
+        }
     }
 }
@@ -0,0 +1,243 @@
+use crate::{config::env_or, toolbox::errors::CustomError};
+use anyhow::anyhow;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand::Rng;
+use std::{convert::TryFrom, sync::Arc};
+
+// what the dummy hash is made of, nobody logs in with it
+static DUMMY_PASSWORD: &str = "not the password of anybody";
+
+// A way of hashing passwords. New hashes are made with the current scheme,
+// the older ones are only there to verify the hashes already in the database.
+pub trait HashingScheme: Send + Sync {
+    // tells from the hash string whether this scheme made it
+    fn recognizes(&self, hash: &str) -> bool;
+    fn hash(&self, password: &str) -> Result<String, CustomError>;
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, CustomError>;
+    // false if the hash was made with weaker or different parameters
+    fn is_up_to_date(&self, _hash: &str) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Mismatch,
+    Match,
+    // the password is right, but its hash should be replaced
+    MatchNeedsRehash,
+}
+
+#[derive(Clone)]
+pub struct PasswordHashing {
+    current: Arc<dyn HashingScheme>,
+    legacy: Vec<Arc<dyn HashingScheme>>,
+    // checked when there is no hash to check, so that it takes as long as when there is
+    dummy_hash: Arc<String>,
+}
+
+impl PasswordHashing {
+    // ARGON2_MEMORY_KIB: 19456 (19 MiB) by default
+    // ARGON2_ITERATIONS: 2 by default
+    // ARGON2_PARALLELISM: 1 by default
+    pub fn from_env() -> anyhow::Result<Self> {
+        let argon2id = Argon2idScheme::new(
+            env_or("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST)?,
+            env_or("ARGON2_ITERATIONS", Params::DEFAULT_T_COST)?,
+            env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST)?,
+        )?;
+        Self::new(Arc::new(argon2id), vec![Arc::new(BcryptScheme)])
+            .map_err(|error| anyhow!("Could not make the dummy hash: {}", error))
+    }
+
+    fn new(
+        current: Arc<dyn HashingScheme>,
+        legacy: Vec<Arc<dyn HashingScheme>>,
+    ) -> Result<Self, CustomError> {
+        let dummy_hash = Arc::new(current.hash(DUMMY_PASSWORD)?);
+        Ok(Self {
+            current,
+            legacy,
+            dummy_hash,
+        })
+    }
+
+    // cheap parameters, to keep the tests fast
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        let argon2id = Argon2idScheme::new(1024, 1, 1).unwrap();
+        Self::new(Arc::new(argon2id), vec![Arc::new(BcryptScheme)]).unwrap()
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, CustomError> {
+        self.current.hash(password)
+    }
+
+    pub fn verify(
+        &self,
+        password: &str,
+        hash: &str,
+    ) -> Result<Verification, CustomError> {
+        // the accounts made through an identity provider have no password
+        if hash.is_empty() {
+            self.verify_nothing(password);
+            return Ok(Verification::Mismatch);
+        }
+        if self.current.recognizes(hash) {
+            return Ok(
+                match (
+                    self.current.verify(password, hash)?,
+                    self.current.is_up_to_date(hash),
+                ) {
+                    (false, _) => Verification::Mismatch,
+                    (true, true) => Verification::Match,
+                    (true, false) => Verification::MatchNeedsRehash,
+                },
+            );
+        }
+        match self.legacy.iter().find(|scheme| scheme.recognizes(hash)) {
+            Some(scheme) if scheme.verify(password, hash)? => {
+                Ok(Verification::MatchNeedsRehash)
+            }
+            Some(_) => Ok(Verification::Mismatch),
+            None => Err(CustomError::new(
+                500,
+                "The stored password hash has an unknown format".to_string(),
+            )),
+        }
+    }
+
+    // takes the time of a verification, for the logins of usernames nobody has:
+    // answering faster would tell which usernames exist
+    pub fn verify_nothing(&self, password: &str) {
+        let _ = self.current.verify(password, &self.dummy_hash);
+    }
+}
+
+pub struct Argon2idScheme {
+    params: Params,
+}
+
+impl Argon2idScheme {
+    pub fn new(
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> anyhow::Result<Self> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|error| anyhow!("Invalid Argon2 parameters: {}", error))?;
+        Ok(Self { params })
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl HashingScheme for Argon2idScheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2id$")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, CustomError> {
+        let salt = SaltString::encode_b64(&rand::thread_rng().gen::<[u8; 16]>())?;
+        let hash = self.hasher().hash_password(password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, CustomError> {
+        let parsed_hash = PasswordHash::new(hash)?;
+        match self
+            .hasher()
+            .verify_password(password.as_bytes(), &parsed_hash)
+        {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn is_up_to_date(&self, hash: &str) -> bool {
+        let parsed_hash = match PasswordHash::new(hash) {
+            Ok(parsed_hash) => parsed_hash,
+            Err(_) => return false,
+        };
+        match Params::try_from(&parsed_hash) {
+            Ok(params) => {
+                parsed_hash.version == Some(Version::V0x13.into())
+                    && params.m_cost() == self.params.m_cost()
+                    && params.t_cost() == self.params.t_cost()
+                    && params.p_cost() == self.params.p_cost()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// what the passwords were hashed with before Argon2id
+pub struct BcryptScheme;
+
+impl HashingScheme for BcryptScheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, CustomError> {
+        Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?)
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, CustomError> {
+        Ok(bcrypt::verify(password, hash)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // cheap parameters, to keep the tests fast
+    fn hashing(iterations: u32) -> PasswordHashing {
+        let argon2id = Argon2idScheme::new(1024, iterations, 1).unwrap();
+        PasswordHashing::new(Arc::new(argon2id), vec![Arc::new(BcryptScheme)]).unwrap()
+    }
+
+    #[test]
+    fn new_hashes_are_argon2id() {
+        let hashing = hashing(1);
+        let hash = hashing.hash("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(
+            hashing.verify("correct horse", &hash).unwrap(),
+            Verification::Match
+        );
+        assert_eq!(
+            hashing.verify("wrong horse", &hash).unwrap(),
+            Verification::Mismatch
+        );
+    }
+
+    #[test]
+    fn bcrypt_hashes_are_verified_and_flagged_for_rehash() {
+        let hash = bcrypt::hash("correct horse", 4).unwrap();
+        assert_eq!(
+            hashing(1).verify("correct horse", &hash).unwrap(),
+            Verification::MatchNeedsRehash
+        );
+        assert_eq!(
+            hashing(1).verify("wrong horse", &hash).unwrap(),
+            Verification::Mismatch
+        );
+    }
+
+    #[test]
+    fn changed_parameters_call_for_a_rehash() {
+        let hash = hashing(1).hash("correct horse").unwrap();
+        assert_eq!(
+            hashing(2).verify("correct horse", &hash).unwrap(),
+            Verification::MatchNeedsRehash
+        );
+    }
+}
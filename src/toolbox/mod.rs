@@ -1,3 +1,11 @@
+pub mod authenticated_user;
 pub mod errors;
+pub mod hashing;
+pub mod pagination;
+pub mod password_policy;
 pub mod ping;
-pub mod uid_extractor;
+pub mod secure_token;
+pub mod session_cookies;
+pub mod totp;
+pub mod user_cache;
+pub mod username;
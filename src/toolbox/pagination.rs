@@ -0,0 +1,21 @@
+use serde::Deserialize;
+
+static DEFAULT_LIMIT: i64 = 50;
+static MAX_LIMIT: i64 = 200;
+
+// ?limit=&offset= of the listing routes
+#[derive(Deserialize, Debug)]
+pub struct Page {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl Page {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
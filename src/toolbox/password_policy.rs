@@ -0,0 +1,169 @@
+use crate::{config::env_or, toolbox::errors::CustomError};
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::{collections::HashSet, env, fs, sync::Arc};
+
+static COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+// bcrypt, which the older hashes use, ignores whatever comes after
+static BCRYPT_MAX_BYTES: usize = 72;
+
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize, // characters
+    pub max_length: usize, // bytes
+    deny_list: Arc<HashSet<String>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Violation {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl PasswordPolicy {
+    // PASSWORD_MIN_LENGTH: 10 characters by default
+    // PASSWORD_MAX_LENGTH: 72 bytes by default, and at most
+    // PASSWORD_DENY_LIST: a file of forbidden passwords, one per line,
+    //                     on top of the built-in list of common passwords
+    pub fn from_env() -> anyhow::Result<Self> {
+        let min_length = env_or("PASSWORD_MIN_LENGTH", 10)?;
+        let max_length = env_or("PASSWORD_MAX_LENGTH", BCRYPT_MAX_BYTES)?;
+        if max_length > BCRYPT_MAX_BYTES {
+            bail!(
+                "PASSWORD_MAX_LENGTH can't exceed {} bytes",
+                BCRYPT_MAX_BYTES
+            );
+        }
+        if min_length > max_length {
+            bail!("PASSWORD_MIN_LENGTH is greater than PASSWORD_MAX_LENGTH");
+        }
+
+        let mut deny_list = parse_deny_list(COMMON_PASSWORDS);
+        if let Ok(path) = env::var("PASSWORD_DENY_LIST") {
+            let content = fs::read_to_string(&path).with_context(|| {
+                format!("Could not read the password deny list {}", path)
+            })?;
+            deny_list.extend(parse_deny_list(&content));
+        }
+
+        Ok(Self {
+            min_length,
+            max_length,
+            deny_list: Arc::new(deny_list),
+        })
+    }
+
+    // the defaults, whatever the environment
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self {
+            min_length: 10,
+            max_length: BCRYPT_MAX_BYTES,
+            deny_list: Arc::new(parse_deny_list(COMMON_PASSWORDS)),
+        }
+    }
+
+    // all the rules are checked, so that the user can fix everything at once
+    pub fn violations(&self, username: &str, password: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            violations.push(Violation {
+                rule: "min_length",
+                message: format!(
+                    "The password must be at least {} characters long",
+                    self.min_length
+                ),
+            });
+        }
+        if password.len() > self.max_length {
+            violations.push(Violation {
+                rule: "max_length",
+                message: format!(
+                    "The password must not be longer than {} bytes",
+                    self.max_length
+                ),
+            });
+        }
+        if !username.is_empty() && password.to_lowercase() == username.to_lowercase() {
+            violations.push(Violation {
+                rule: "not_username",
+                message: "The password must not be the username".to_string(),
+            });
+        }
+        if self.deny_list.contains(&password.to_lowercase()) {
+            violations.push(Violation {
+                rule: "not_common",
+                message: "This password is too common".to_string(),
+            });
+        }
+
+        violations
+    }
+
+    pub fn check(&self, username: &str, password: &str) -> Result<(), CustomError> {
+        let violations = self.violations(username, password);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(CustomError::new(
+            422,
+            "The password doesn't comply with the password policy".to_string(),
+        )
+        .with_details(serde_json::json!({ "violations": violations })))
+    }
+}
+
+fn parse_deny_list(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules(violations: Vec<Violation>) -> Vec<&'static str> {
+        violations
+            .into_iter()
+            .map(|violation| violation.rule)
+            .collect()
+    }
+
+    #[test]
+    fn a_decent_password_passes() {
+        assert!(PasswordPolicy::for_tests()
+            .check("ann", "correct horse battery staple")
+            .is_ok());
+    }
+
+    #[test]
+    fn every_failed_rule_is_listed() {
+        assert_eq!(
+            rules(PasswordPolicy::for_tests().violations("Qwerty", "qwerty")),
+            vec!["min_length", "not_username", "not_common"]
+        );
+    }
+
+    #[test]
+    fn max_length_counts_bytes() {
+        let password = "é".repeat(40); // 40 characters, 80 bytes
+        assert_eq!(
+            rules(PasswordPolicy::for_tests().violations("ann", &password)),
+            vec!["max_length"]
+        );
+    }
+
+    #[test]
+    fn violations_are_a_422() {
+        let error = PasswordPolicy::for_tests()
+            .check("ann", "short")
+            .unwrap_err();
+        assert_eq!(error.error_status_code, 422);
+        assert!(error.details.is_some());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    config::{env_or, Config},
+    jwt::TokenResponse,
+    toolbox::{errors::CustomError, secure_token},
+};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::HttpResponseBuilder,
+    http::Method,
+    HttpMessage, HttpResponse,
+};
+use anyhow::bail;
+use serde::Serialize;
+use std::env;
+
+pub static SESSION_COOKIE: &str = "ages_session";
+pub static REFRESH_COOKIE: &str = "ages_refresh";
+pub static CSRF_COOKIE: &str = "ages_csrf";
+pub static CSRF_HEADER: &str = "X-CSRF-Token";
+// the refresh token is only needed by /auth/refresh and /auth/logout
+static REFRESH_COOKIE_PATH: &str = "/auth";
+
+// Sessions kept in cookies, out of reach of the page's JavaScript, for browser clients.
+// A page of another site can make the browser send them, but not read the CSRF cookie.
+#[derive(Clone)]
+pub struct CookieConfig {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+}
+
+// sent instead of a TokenResponse, the tokens are in the cookies
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub expires_in: i64, // seconds
+    pub csrf_token: String,
+}
+
+impl CookieConfig {
+    // SESSION_COOKIES: false by default, tokens are then only given in the body
+    // COOKIE_SECURE: true by default, false for a front-end on plain http
+    //                while developing
+    // COOKIE_SAME_SITE: "strict" by default, or "lax"
+    // COOKIE_DOMAIN: the host of the API by default
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        if !env_or("SESSION_COOKIES", false)? {
+            return Ok(None);
+        }
+        let same_site = match env_or("COOKIE_SAME_SITE", "strict".to_string())?
+            .to_lowercase()
+            .as_str()
+        {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            other => bail!("COOKIE_SAME_SITE should be strict or lax, not '{}'", other),
+        };
+        Ok(Some(Self {
+            secure: env_or("COOKIE_SECURE", true)?,
+            same_site,
+            domain: env::var("COOKIE_DOMAIN").ok(),
+        }))
+    }
+
+    fn cookie(
+        &self,
+        name: &'static str,
+        value: String,
+        path: &'static str,
+        max_age: i64, // seconds
+    ) -> Cookie<'static> {
+        let mut cookie = Cookie::build(name, value)
+            .path(path)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .http_only(name != CSRF_COOKIE)
+            .max_age(max_age)
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+// the tokens of a login, in the body or in cookies depending on the mode
+pub fn token_response(token_response: TokenResponse, config: &Config) -> HttpResponse {
+    let cookies = match &config.session_cookies {
+        Some(cookies) => cookies,
+        None => return HttpResponse::Ok().json(token_response),
+    };
+    let session = cookies.cookie(
+        SESSION_COOKIE,
+        token_response.token,
+        "/",
+        token_response.expires_in,
+    );
+    let refresh = cookies.cookie(
+        REFRESH_COOKIE,
+        token_response.refresh_token,
+        REFRESH_COOKIE_PATH,
+        config.tokens.refresh_token_lifetime,
+    );
+    // the only one readable by the page, which sends it back in the X-CSRF-Token header
+    let csrf_token = secure_token::generate();
+    let csrf = cookies.cookie(
+        CSRF_COOKIE,
+        csrf_token.clone(),
+        "/",
+        config.tokens.refresh_token_lifetime,
+    );
+
+    HttpResponse::Ok()
+        .cookie(session)
+        .cookie(refresh)
+        .cookie(csrf)
+        .json(SessionResponse {
+            expires_in: token_response.expires_in,
+            csrf_token,
+        })
+}
+
+// on logout
+pub fn clear<'a>(
+    response: &'a mut HttpResponseBuilder,
+    config: &Config,
+) -> &'a mut HttpResponseBuilder {
+    if let Some(cookies) = &config.session_cookies {
+        response
+            .del_cookie(&cookies.cookie(SESSION_COOKIE, String::new(), "/", 0))
+            .del_cookie(&cookies.cookie(
+                REFRESH_COOKIE,
+                String::new(),
+                REFRESH_COOKIE_PATH,
+                0,
+            ))
+            .del_cookie(&cookies.cookie(CSRF_COOKIE, String::new(), "/", 0));
+    }
+    response
+}
+
+// the refresh token of a browser client, which has to prove it's not a forged request
+pub fn refresh_token<R: HttpMessage>(
+    request: &R,
+    config: &Config,
+) -> Result<Option<String>, CustomError> {
+    if config.session_cookies.is_none() {
+        return Ok(None);
+    }
+    match request.cookie(REFRESH_COOKIE) {
+        Some(cookie) => {
+            check_csrf(request)?;
+            Ok(Some(cookie.value().to_string()))
+        }
+        None => Ok(None),
+    }
+}
+
+// double submit: the header has to repeat the cookie
+pub fn check_csrf<R: HttpMessage>(request: &R) -> Result<(), CustomError> {
+    let cookie = request.cookie(CSRF_COOKIE);
+    let header = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|header| header.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header))
+            if ring::constant_time::verify_slices_are_equal(
+                cookie.value().as_bytes(),
+                header.as_bytes(),
+            )
+            .is_ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(CustomError::new(
+            403,
+            "Missing or wrong X-CSRF-Token header".to_string(),
+        )),
+    }
+}
+
+// the requests that can't change anything don't need the CSRF token
+pub fn needs_csrf(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn the_csrf_header_has_to_repeat_the_cookie() {
+        let with = |header: Option<&str>| {
+            let mut request =
+                TestRequest::default().cookie(Cookie::new(CSRF_COOKIE, "secret"));
+            if let Some(header) = header {
+                request = request.header(CSRF_HEADER, header);
+            }
+            check_csrf(&request.to_http_request()).is_ok()
+        };
+        assert!(with(Some("secret")));
+        assert!(!with(Some("guess")));
+        assert!(!with(None));
+        assert!(check_csrf(&TestRequest::default().to_http_request()).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use rand::{rngs::OsRng, Rng, RngCore};
+use ring::hmac;
+
+// RFC 6238, with the parameters every authenticator app supports
+static DIGITS: u32 = 6;
+static PERIOD: i64 = 30; // seconds
+
+// codes of the previous and next periods are accepted too, for clock drift
+static ALLOWED_DRIFT: i64 = 1;
+static SECRET_BYTES: usize = 20;
+static RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+// base32, as authenticator apps expect it
+pub fn generate_secret() -> String {
+    let mut bytes = vec![0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+// what the QR code contains
+pub fn otpauth_uri(secret: &str, issuer: &str, account_name: &str) -> String {
+    let encode = |text: &str| {
+        percent_encoding::utf8_percent_encode(text, percent_encoding::NON_ALPHANUMERIC)
+            .to_string()
+    };
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode(issuer),
+        encode(account_name),
+        secret,
+        encode(issuer),
+        DIGITS,
+        PERIOD
+    )
+}
+
+pub fn time_step(unix_time: i64) -> i64 {
+    unix_time / PERIOD
+}
+
+pub fn code_at_step(secret: &str, step: i64) -> Option<String> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    Some(hotp(&key, step as u64))
+}
+
+// returns the time step the code belongs to, so that it can't be replayed
+pub fn verify(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let code = code.trim();
+    let current_step = time_step(unix_time);
+    (current_step - ALLOWED_DRIFT..=current_step + ALLOWED_DRIFT).find(|step| {
+        code_at_step(secret, *step)
+            .map(|expected| constant_time_eq(expected.as_bytes(), code.as_bytes()))
+            .unwrap_or(false)
+    })
+}
+
+// RFC 4226, with HMAC-SHA1
+fn hotp(key: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let digest = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = digest.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    ring::constant_time::verify_slices_are_equal(a, b).is_ok()
+}
+
+// like "k7dm2-xq9hp", without the characters that are easily mistaken for one another
+pub fn generate_recovery_code() -> String {
+    let mut rng = OsRng;
+    let mut pick = || {
+        (0..5)
+            .map(|_| {
+                RECOVERY_CODE_ALPHABET[rng.gen_range(0, RECOVERY_CODE_ALPHABET.len())]
+                    as char
+            })
+            .collect::<String>()
+    };
+    format!("{}-{}", pick(), pick())
+}
+
+// users type recovery codes however they like
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|character| character.is_ascii_alphanumeric())
+        .map(|character| character.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the SHA1 test vectors of RFC 6238, appendix B, truncated to 6 digits
+    static RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_6238_test_vectors() {
+        let secret =
+            base32::encode(base32::Alphabet::RFC4648 { padding: false }, RFC_SECRET);
+        for (unix_time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ]
+        .iter()
+        {
+            assert_eq!(code_at_step(&secret, time_step(*unix_time)).unwrap(), *code);
+        }
+    }
+
+    #[test]
+    fn codes_of_neighbouring_periods_are_accepted() {
+        let secret = generate_secret();
+        let code = code_at_step(&secret, time_step(1_000_000)).unwrap();
+        assert_eq!(
+            verify(&secret, &code, 1_000_000 + 30),
+            Some(time_step(1_000_000))
+        );
+        assert_eq!(verify(&secret, &code, 1_000_000 + 90), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_normalized() {
+        let code = generate_recovery_code();
+        assert_eq!(
+            normalize_recovery_code(&code.to_uppercase().replace('-', " ")),
+            normalize_recovery_code(&code)
+        );
+    }
+}
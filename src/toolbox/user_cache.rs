@@ -0,0 +1,169 @@
+use crate::{config::env_or, models::user::User};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// The users the authentication middleware found recently, so that most requests
+// don't need the database to know whether their user still exists and may log in.
+// The controllers that change what the middleware checks invalidate the entry;
+// the TTL bounds how stale it is for another instance of the API. Only the users:
+// the revoked tokens and the sessions are still checked in the database.
+#[derive(Clone)]
+pub struct UserCache {
+    entries: Arc<Mutex<HashMap<i32, CachedUser>>>,
+    capacity: usize,
+    ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+struct CachedUser {
+    user: User,
+    cached_at: Instant,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64, // between 0 and 1, 0 before the first lookup
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl UserCache {
+    // USER_CACHE_TTL_SECONDS: 30 by default, 0 turns the cache off
+    // USER_CACHE_CAPACITY: 10000 users by default
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self::new(
+            env_or("USER_CACHE_CAPACITY", 10_000)?,
+            Duration::from_secs(env_or("USER_CACHE_TTL_SECONDS", 30)?),
+        ))
+    }
+
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            capacity,
+            ttl,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn get(&self, uid: i32) -> Option<User> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&uid) {
+            Some(cached) if cached.cached_at.elapsed() < self.ttl => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(cached.user.clone())
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, user: User) {
+        if self.capacity == 0 || self.ttl.as_secs() == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&user.id) {
+            let ttl = self.ttl;
+            entries.retain(|_, cached| cached.cached_at.elapsed() < ttl);
+            // still full of fresh entries: the oldest one goes
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.cached_at)
+                    .map(|(uid, _)| *uid);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            user.id,
+            CachedUser {
+                user,
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    // after any change to the user's row that the middleware looks at
+    pub fn invalidate(&self, uid: i32) {
+        self.entries.lock().unwrap().remove(&uid);
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheMetrics {
+            hits,
+            misses,
+            hit_rate: match hits + misses {
+                0 => 0.0,
+                lookups => hits as f64 / lookups as f64,
+            },
+            entries: self.entries.lock().unwrap().len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(id: i32) -> User {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "username": format!("user{}", id),
+            "password": "",
+            "token_version": 0,
+            "totp_secret": null,
+            "totp_enabled": false,
+            "totp_last_step": null,
+            "email": null,
+            "email_verified": false,
+            "role": "user",
+            "disabled_at": null,
+            "password_reset_required": false,
+            "deletion_requested_at": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn bounded_and_invalidated() {
+        let cache = UserCache::new(2, Duration::from_secs(60));
+        assert!(cache.get(1).is_none());
+        cache.insert(user(1));
+        cache.insert(user(2));
+        cache.insert(user(3)); // the oldest goes
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(3).map(|user| user.id), Some(3));
+        cache.invalidate(3);
+        assert!(cache.get(3).is_none());
+
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses, metrics.entries), (1, 3, 1));
+        assert!((metrics.hit_rate - 0.25).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn a_zero_ttl_turns_the_cache_off() {
+        let cache = UserCache::new(2, Duration::from_secs(0));
+        cache.insert(user(1));
+        assert!(cache.get(1).is_none());
+    }
+}
@@ -0,0 +1,35 @@
+use crate::toolbox::errors::CustomError;
+use unicode_normalization::UnicodeNormalization;
+
+// NFKC, so that the lookalike forms of a character ("ｂｏｂ") make the same name
+pub fn normalize(username: &str) -> Result<String, CustomError> {
+    let normalized: String = username.nfkc().collect::<String>().trim().to_string();
+    if normalized.is_empty() {
+        return Err(CustomError::new(400, "Username is empty".to_string()));
+    }
+    if normalized.chars().any(char::is_control) {
+        return Err(CustomError::new(
+            400,
+            "The username can't contain control characters".to_string(),
+        ));
+    }
+    Ok(normalized)
+}
+
+// what tells usernames apart, like the unique index of the database
+pub fn fold(username: &str) -> String {
+    username.nfkc().collect::<String>().trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookalike_usernames_are_the_same() {
+        assert_eq!(normalize(" ｂｏｂ ").unwrap(), "bob");
+        assert_eq!(fold("BOB"), fold("ｂｏｂ"));
+        assert!(normalize("  ").is_err());
+        assert!(normalize("bob\u{7}").is_err());
+    }
+}